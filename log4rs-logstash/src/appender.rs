@@ -2,24 +2,75 @@ use anyhow::Result as AnyResult;
 use log::Level as LogLevel;
 use log::Record;
 use log4rs::append::Append;
+use qoollo_logstash_rs::error_handler;
+#[cfg(feature = "kv")]
+use qoollo_logstash_rs::KeyValuePlacement;
 use qoollo_logstash_rs::LogStashRecord;
 use qoollo_logstash_rs::Sender;
-use qoollo_logstash_rs::{BufferedSender, TcpSender};
+use qoollo_logstash_rs::{
+    BufferedSender, Codec, Compression, DedupConfig, ErrorEvent, ErrorHandler, JsonLinesCodec,
+    Metrics, MetricsSnapshot, OutputSchema, OverflowPolicy, OversizedPolicy, ParallelSender,
+    PrintErrorHandler, Processor, ProcessorChain, Proxy, RateLimitConfig, RedactionConfig,
+    RestartPolicy, RetryConfig, SamplingConfig, SizeLimits, SpoolConfig, TcpSender, TlsBackend,
+    WorkerOrdering,
+};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
-pub struct Appender<S> {
+pub struct Appender<S: Sender> {
     sender: S,
     extra_fields: HashMap<String, Value>,
+    processors: ProcessorChain,
+    #[cfg(feature = "kv")]
+    key_values: KeyValuePlacement,
+    shutdown_timeout: Duration,
+    error_handler: SharedErrorHandler,
 }
 
-impl<S> std::fmt::Debug for Appender<S> {
+impl<S: Sender> std::fmt::Debug for Appender<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}::Appender", module_path!())
     }
 }
 
+type MetricsCallback = Box<dyn Fn(&MetricsSnapshot) + Send + Sync>;
+
+struct MetricsReporter {
+    interval: Duration,
+    callback: MetricsCallback,
+}
+
+impl std::fmt::Debug for MetricsReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MetricsReporter({:?})", self.interval)
+    }
+}
+
+#[derive(Clone)]
+struct SharedErrorHandler(Arc<dyn ErrorHandler>);
+
+impl std::fmt::Debug for SharedErrorHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ErrorHandler")
+    }
+}
+
+struct SharedCodec(Arc<dyn Codec>);
+
+impl std::fmt::Debug for SharedCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Codec")
+    }
+}
+
+impl SharedErrorHandler {
+    fn report(&self, error: qoollo_logstash_rs::Error, dropped: u64) {
+        error_handler::report(&*self.0, &ErrorEvent::new(error, dropped));
+    }
+}
+
 #[derive(Debug)]
 pub struct AppenderBuilder {
     hostname: String,
@@ -29,9 +80,37 @@ pub struct AppenderBuilder {
     connection_timeout: Option<Duration>,
     ignore_buffer: LogLevel,
     use_tls: bool,
+    tls_backend: TlsBackend,
+    proxy: Option<Proxy>,
+    compression: Option<Compression>,
     error_period: Duration,
     extra_fields: HashMap<String, Value>,
     log_queue_len: usize,
+    spool: Option<SpoolConfig>,
+    retry: Option<RetryConfig>,
+    overflow_policy: OverflowPolicy,
+    level_overflow_policies: HashMap<LogLevel, OverflowPolicy>,
+    priority_level: Option<LogLevel>,
+    priority_queue_len: Option<usize>,
+    max_batch_bytes: Option<usize>,
+    oversized_policy: OversizedPolicy,
+    memory_budget: Option<usize>,
+    shutdown_timeout: Duration,
+    metrics_reporter: Option<MetricsReporter>,
+    error_handler: Option<SharedErrorHandler>,
+    restart: Option<RestartPolicy>,
+    workers: usize,
+    worker_ordering: WorkerOrdering,
+    rate_limit: Option<RateLimitConfig>,
+    sampling: Option<SamplingConfig>,
+    dedup: Option<DedupConfig>,
+    redaction: Option<RedactionConfig>,
+    limits: Option<SizeLimits>,
+    schema: OutputSchema,
+    codec: Option<SharedCodec>,
+    processors: ProcessorChain,
+    #[cfg(feature = "kv")]
+    key_values: KeyValuePlacement,
 }
 
 impl Default for AppenderBuilder {
@@ -43,10 +122,38 @@ impl Default for AppenderBuilder {
             buffer_lifetime: Some(Duration::from_secs(1)),
             connection_timeout: Some(Duration::from_secs(10)),
             use_tls: false,
+            tls_backend: TlsBackend::default(),
+            proxy: None,
+            compression: None,
             ignore_buffer: LogLevel::Error,
             error_period: Duration::from_secs(10),
             extra_fields: Default::default(),
             log_queue_len: 1000,
+            spool: None,
+            retry: None,
+            overflow_policy: OverflowPolicy::DropNewest,
+            level_overflow_policies: Default::default(),
+            priority_level: None,
+            priority_queue_len: None,
+            max_batch_bytes: None,
+            oversized_policy: OversizedPolicy::default(),
+            memory_budget: None,
+            shutdown_timeout: Duration::from_secs(5),
+            metrics_reporter: None,
+            error_handler: None,
+            restart: Some(RestartPolicy::default()),
+            workers: 1,
+            worker_ordering: WorkerOrdering::default(),
+            rate_limit: None,
+            sampling: None,
+            dedup: None,
+            redaction: None,
+            limits: None,
+            schema: OutputSchema::default(),
+            codec: None,
+            processors: ProcessorChain::new(),
+            #[cfg(feature = "kv")]
+            key_values: KeyValuePlacement::default(),
         }
     }
 }
@@ -100,18 +207,204 @@ impl AppenderBuilder {
         self
     }
 
-    /// Print period for internal logstash errors.
+    /// TLS implementation used for tls connection.
+    pub fn with_tls_backend(mut self, tls_backend: TlsBackend) -> AppenderBuilder {
+        self.tls_backend = tls_backend;
+        self
+    }
+
+    /// Connect to the remote server through HTTP CONNECT or SOCKS5 proxy.
+    pub fn with_proxy(mut self, proxy: Proxy) -> AppenderBuilder {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Compress payload sent to the remote server, the build fails if the algorithm feature is
+    /// not enabled.
+    pub fn with_compression(mut self, compression: Compression) -> AppenderBuilder {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Print period for internal logstash errors, used by default error handler.
     pub fn with_error_period(mut self, error_period: Duration) -> AppenderBuilder {
         self.error_period = error_period;
         self
     }
 
+    /// Receives internal logstash errors instead of printing them to stderr.
+    pub fn with_error_handler(mut self, handler: impl ErrorHandler) -> AppenderBuilder {
+        self.error_handler = Some(SharedErrorHandler(Arc::new(handler)));
+        self
+    }
+
     /// Maximum length of log message queue
     pub fn with_log_queue_len(mut self, log_queue_len: usize) -> AppenderBuilder {
         self.log_queue_len = log_queue_len;
         self
     }
 
+    /// Persist records in on-disk spool until they are delivered
+    pub fn with_spool(mut self, spool: SpoolConfig) -> AppenderBuilder {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// What to do with records when log message queue is full
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> AppenderBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Overrides queue overflow policy for records with the level
+    pub fn with_level_overflow_policy(
+        mut self,
+        level: LogLevel,
+        policy: OverflowPolicy,
+    ) -> AppenderBuilder {
+        self.level_overflow_policies.insert(level, policy);
+        self
+    }
+
+    /// Separate queue for records with the level or more important, which is processed first
+    pub fn with_priority_level(mut self, level: LogLevel) -> AppenderBuilder {
+        self.priority_level = Some(level);
+        self
+    }
+
+    /// Maximum length of priority queue, same as log message queue by default
+    pub fn with_priority_queue_len(mut self, queue_len: usize) -> AppenderBuilder {
+        self.priority_queue_len = Some(queue_len);
+        self
+    }
+
+    /// Maximum serialized size of the batch in bytes, estimated as plain JSON of the records
+    /// whatever the codec and schema
+    pub fn with_max_batch_bytes(mut self, max_batch_bytes: usize) -> AppenderBuilder {
+        self.max_batch_bytes = Some(max_batch_bytes);
+        self
+    }
+
+    /// What to do with a record larger than maximum batch size
+    pub fn with_oversized_policy(mut self, policy: OversizedPolicy) -> AppenderBuilder {
+        self.oversized_policy = policy;
+        self
+    }
+
+    /// Maximum serialized size of records held in memory by log message queue and buffer,
+    /// estimated as plain JSON of the records
+    pub fn with_memory_budget(mut self, bytes: usize) -> AppenderBuilder {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Maximum time to wait for pending records to be sent when the appender is dropped
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> AppenderBuilder {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Calls `callback` with metrics snapshot every `interval`
+    pub fn with_metrics_callback(
+        mut self,
+        interval: Duration,
+        callback: impl Fn(&MetricsSnapshot) + Send + Sync + 'static,
+    ) -> AppenderBuilder {
+        self.metrics_reporter = Some(MetricsReporter {
+            interval,
+            callback: Box::new(callback),
+        });
+        self
+    }
+
+    /// Restart policy of the sender after fatal errors, `None` stops sending on fatal error
+    pub fn with_restart_policy(mut self, restart: Option<RestartPolicy>) -> AppenderBuilder {
+        self.restart = restart;
+        self
+    }
+
+    /// Number of parallel connections to the remote server, each one but the first is served
+    /// by its own worker thread
+    pub fn with_workers(mut self, workers: usize) -> AppenderBuilder {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// How records are distributed between parallel connections
+    pub fn with_worker_ordering(mut self, ordering: WorkerOrdering) -> AppenderBuilder {
+        self.worker_ordering = ordering;
+        self
+    }
+
+    /// Throttle records with global, per target and per level token buckets
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> AppenderBuilder {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Keep only a fraction of low severity records
+    pub fn with_sampling(mut self, sampling: SamplingConfig) -> AppenderBuilder {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    /// Collapse records with the same template within a time window into one with repeat count
+    pub fn with_dedup(mut self, dedup: DedupConfig) -> AppenderBuilder {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Mask or hash sensitive values before records are queued
+    pub fn with_redaction(mut self, redaction: RedactionConfig) -> AppenderBuilder {
+        self.redaction = Some(redaction);
+        self
+    }
+
+    /// Truncate values exceeding the limits before records are queued
+    pub fn with_limits(mut self, limits: SizeLimits) -> AppenderBuilder {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Where to put key-value pairs of records, top-level fields by default
+    #[cfg(feature = "kv")]
+    pub fn with_key_values(mut self, placement: KeyValuePlacement) -> AppenderBuilder {
+        self.key_values = placement;
+        self
+    }
+
+    /// Layout of sent records, e.g. Elastic Common Schema, used by the default JSON lines codec.
+    /// Custom codecs take the schema with their own `with_schema`, setting both fails the build.
+    pub fn with_schema(mut self, schema: OutputSchema) -> AppenderBuilder {
+        self.schema = schema;
+        self
+    }
+
+    /// Encoding of sent records, replaces JSON lines codec and can't be combined with
+    /// [`with_schema`](Self::with_schema)
+    pub fn with_codec(mut self, codec: impl Codec) -> AppenderBuilder {
+        self.codec = Some(SharedCodec(Arc::new(codec)));
+        self
+    }
+
+    /// Append processor to the chain applied to records before sending
+    pub fn with_processor(mut self, processor: impl Processor) -> AppenderBuilder {
+        self.processors = self.processors.with(processor);
+        self
+    }
+
+    /// Replace the chain of processors applied to records before sending
+    pub fn with_processors(mut self, processors: ProcessorChain) -> AppenderBuilder {
+        self.processors = processors;
+        self
+    }
+
+    /// Retry failed batches from memory before sending newer records
+    pub fn with_retry(mut self, retry: RetryConfig) -> AppenderBuilder {
+        self.retry = Some(retry);
+        self
+    }
+
     /// Additional fields to send to logstash
     pub fn with_extra_fields(mut self, extra_fields: HashMap<String, Value>) -> AppenderBuilder {
         self.extra_fields = extra_fields;
@@ -120,21 +413,110 @@ impl AppenderBuilder {
 
     /// Invoke the builder and return a [`Appender`](struct.Appender.html).
     pub fn build(self) -> AnyResult<Appender<BufferedSender>> {
+        let metrics = Metrics::new();
+        let error_period = self.error_period;
+        let error_handler = self
+            .error_handler
+            .unwrap_or_else(|| SharedErrorHandler(Arc::new(PrintErrorHandler::new(error_period))));
+        let (hostname, port, use_tls, connection_timeout) = (
+            self.hostname,
+            self.port,
+            self.use_tls,
+            self.connection_timeout,
+        );
+        let (tls_backend, proxy, compression) = (self.tls_backend, self.proxy, self.compression);
+        if let Some(compression) = &compression {
+            compression.check()?;
+        }
+        let (workers, worker_ordering) = (self.workers, self.worker_ordering);
+        let codec = match (self.codec, self.schema) {
+            (Some(codec), OutputSchema::Legacy) => codec.0,
+            (Some(_), _) => {
+                anyhow::bail!("output schema of a custom codec must be set on the codec")
+            }
+            (None, schema) => Arc::new(JsonLinesCodec::new().with_schema(schema)),
+        };
+        let tcp_metrics = metrics.clone();
+        // Used again to replace the sender after fatal error
+        let make_sender = move || {
+            let senders = (0..workers)
+                .map(|_| {
+                    let mut tcp_sender =
+                        TcpSender::new(hostname.clone(), port, use_tls, connection_timeout)
+                            .with_tls_backend(tls_backend)
+                            .with_codec(codec.clone())
+                            .with_metrics(tcp_metrics.clone());
+                    if let Some(proxy) = &proxy {
+                        tcp_sender = tcp_sender.with_proxy(proxy.clone());
+                    }
+                    if let Some(compression) = compression {
+                        tcp_sender = tcp_sender.with_compression(compression);
+                    }
+                    tcp_sender
+                })
+                .collect();
+            Ok(ParallelSender::new(senders).with_ordering(worker_ordering))
+        };
+        let mut sender = BufferedSender::builder(make_sender()?)
+            .with_buffer_size(self.buffer_size)
+            .with_buffer_lifetime(self.buffer_lifetime)
+            .with_ignore_buffer_level(self.ignore_buffer)
+            .with_error_handler({
+                let error_handler = error_handler.clone();
+                move |event: &ErrorEvent| error_handler.0.handle(event)
+            })
+            .with_log_queue_len(self.log_queue_len)
+            .with_overflow_policy(self.overflow_policy)
+            .with_oversized_policy(self.oversized_policy)
+            .with_metrics(metrics);
+        for (level, policy) in self.level_overflow_policies {
+            sender = sender.with_level_overflow_policy(level, policy);
+        }
+        if let Some(level) = self.priority_level {
+            let queue_len = self.priority_queue_len.unwrap_or(self.log_queue_len);
+            sender = sender.with_priority_lane(level, queue_len);
+        }
+        if let Some(max_batch_bytes) = self.max_batch_bytes {
+            sender = sender.with_max_batch_bytes(max_batch_bytes);
+        }
+        if let Some(bytes) = self.memory_budget {
+            sender = sender.with_memory_budget(bytes);
+        }
+        if let Some(rate_limit) = self.rate_limit {
+            sender = sender.with_rate_limit(rate_limit);
+        }
+        if let Some(sampling) = self.sampling {
+            sender = sender.with_sampling(sampling);
+        }
+        if let Some(dedup) = self.dedup {
+            sender = sender.with_dedup(dedup);
+        }
+        if let Some(redaction) = self.redaction {
+            sender = sender.with_redaction(redaction);
+        }
+        if let Some(limits) = self.limits {
+            sender = sender.with_limits(limits);
+        }
+        if let Some(spool) = self.spool {
+            sender = sender.with_spool(spool);
+        }
+        if let Some(retry) = self.retry {
+            sender = sender.with_retry(retry);
+        }
+        if let Some(restart) = self.restart {
+            sender = sender.with_restart(restart, make_sender);
+        }
+        if let Some(reporter) = self.metrics_reporter {
+            sender = sender.with_metrics_callback(reporter.interval, reporter.callback);
+        }
         Ok(Appender {
-            sender: BufferedSender::new(
-                TcpSender::new(
-                    self.hostname,
-                    self.port,
-                    self.use_tls,
-                    self.connection_timeout,
-                ),
-                self.buffer_size,
-                self.buffer_lifetime,
-                self.ignore_buffer,
-                self.error_period,
-                self.log_queue_len,
-            ),
+            sender: sender.build()?,
             extra_fields: self.extra_fields,
+            processors: self.processors,
+            #[cfg(feature = "kv")]
+            key_values: self.key_values,
+            shutdown_timeout: self.shutdown_timeout,
+            error_handler,
         })
     }
 }
@@ -147,24 +529,57 @@ where
         AppenderBuilder::default()
     }
 
-    fn try_flush(&self) -> AnyResult<()> {
-        self.sender.flush()?;
+    /// Sends pending records and stops the sender, waiting up to the timeout
+    pub fn shutdown(&self, timeout: Duration) -> AnyResult<()> {
+        self.sender.shutdown(timeout)?;
         Ok(())
     }
 }
 
+impl Appender<BufferedSender> {
+    /// Waits until all records appended before the call are sent, returns number of records
+    /// still pending if the timeout expires. Fails if records were dropped instead of sent.
+    pub fn flush_blocking(&self, timeout: Duration) -> AnyResult<usize> {
+        Ok(self.sender.flush_blocking(timeout)?)
+    }
+
+    /// Current counters of sent and dropped records, connection and errors
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.sender.metrics()
+    }
+}
+
 impl<S> Append for Appender<S>
 where
     S: Sender + Sync + Send + 'static,
 {
     fn append(&self, record: &Record) -> AnyResult<()> {
-        self.sender
-            .send(LogStashRecord::from_record(record).with_data_from_map(&self.extra_fields))?;
+        #[cfg(feature = "kv")]
+        let event = LogStashRecord::from_record_with_key_values(record, &self.key_values);
+        #[cfg(not(feature = "kv"))]
+        let event = LogStashRecord::from_record(record);
+        let event = event.with_data_from_map(&self.extra_fields);
+        if let Some(event) = self.processors.process(event) {
+            self.sender.send(event)?;
+        }
         Ok(())
     }
     fn flush(&self) {
-        if let Err(err) = self.try_flush() {
-            eprintln!("Logstash appender failed to flush: {}", err);
+        if let Err(err) = self.sender.flush() {
+            self.error_handler.report(err, 0);
+        }
+    }
+}
+
+impl<S: Sender> Drop for Appender<S> {
+    fn drop(&mut self) {
+        // Appenders are dropped on log4rs config reload, so the wait is bounded
+        if let Err(err) = self.sender.shutdown(self.shutdown_timeout) {
+            let dropped = match err {
+                qoollo_logstash_rs::Error::ShutdownTimeout(pending) => pending as u64,
+                _ => 0,
+            };
+            self.error_handler.report(err, dropped);
         }
     }
 }
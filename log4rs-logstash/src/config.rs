@@ -5,9 +5,21 @@ use serde_json::Value;
 use crate::appender::AppenderBuilder;
 use anyhow::Result as AnyResult;
 use log::Level as LogLevel;
+use qoollo_logstash_rs::processor::{AddFields, DropRecords, RemoveFields, RenameField, Route};
+use qoollo_logstash_rs::redact::Regex;
+#[cfg(feature = "kv")]
+use qoollo_logstash_rs::KeyValuePlacement;
+use qoollo_logstash_rs::{
+    CborCodec, Compression, Condition, DedupConfig, FsyncPolicy, JsonArrayCodec, MsgpackCodec,
+    OutputSchema, OverflowPolicy, OversizedPolicy, Processor, ProcessorChain, Proxy, RateLimit,
+    RateLimitConfig, RedactionConfig, Replacement, RestartPolicy, RetryConfig, RetryDropPolicy,
+    SamplingConfig, SizeLimits, SpoolConfig, TlsBackend, WorkerOrdering,
+};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 
+#[derive(Default)]
 struct AppenderDeserializer {
     extra_fields: Option<HashMap<String, Value>>
 }
@@ -32,25 +44,396 @@ pub struct AppenderConfig {
     #[serde(with = "humantime_serde")]
     connection_timeout: Option<Duration>,
     use_tls: Option<bool>,
+    tls_backend: Option<TlsBackend>,
+    proxy: Option<Proxy>,
+    compression: Option<Compression>,
     #[serde(default)]
     #[serde(with = "humantime_serde")]
     error_period: Option<Duration>,
     extra_fields: Option<HashMap<String, Value>>,
     log_queue_len: Option<usize>,
+    /// `drop_newest`, `drop_oldest`, `block_forever` or blocking timeout
+    overflow_policy: Option<String>,
+    overflow_policy_levels: Option<HashMap<LogLevel, String>>,
+    priority_level: Option<LogLevel>,
+    priority_queue_len: Option<usize>,
+    max_batch_bytes: Option<usize>,
+    oversized_policy: Option<OversizedPolicy>,
+    memory_budget: Option<usize>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    shutdown_timeout: Option<Duration>,
+    restart: Option<RestartSettings>,
+    workers: Option<usize>,
+    worker_ordering: Option<WorkerOrdering>,
+    rate_limit: Option<RateLimitSettings>,
+    sampling: Option<SamplingSettings>,
+    dedup: Option<DedupSettings>,
+    redaction: Option<RedactionSettings>,
+    limits: Option<LimitsSettings>,
+    schema: Option<OutputSchema>,
+    codec: Option<CodecSettings>,
+    processors: Option<Vec<ProcessorSettings>>,
+    #[cfg(feature = "kv")]
+    key_values: Option<KeyValuePlacement>,
+    spool: Option<SpoolSettings>,
+    retry: Option<RetrySettings>,
 }
 
-impl AppenderDeserializer {
-    fn new(extra_fields: Option<HashMap<String, Value>>) -> Self {
-        Self {
-            extra_fields
+fn parse_overflow_policy(policy: &str) -> AnyResult<OverflowPolicy> {
+    Ok(match policy {
+        "drop_newest" => OverflowPolicy::DropNewest,
+        "drop_oldest" => OverflowPolicy::DropOldest,
+        "block_forever" => OverflowPolicy::BlockForever,
+        timeout => OverflowPolicy::Block(humantime_serde::re::humantime::parse_duration(timeout)?),
+    })
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RestartSettings {
+    /// Restarts after fatal errors are disabled if `false`
+    enabled: Option<bool>,
+    max_restarts: Option<usize>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    backoff: Option<Duration>,
+}
+
+impl RestartSettings {
+    fn into_policy(self) -> Option<RestartPolicy> {
+        if self.enabled == Some(false) {
+            return None;
+        }
+        let mut policy = RestartPolicy::default();
+        if let Some(max_restarts) = self.max_restarts {
+            policy = policy.with_max_restarts(max_restarts);
+        }
+        if let Some(backoff) = self.backoff {
+            policy = policy.with_backoff(backoff);
         }
+        Some(policy)
     }
 }
 
-impl Default for AppenderDeserializer {
-    fn default() -> Self {
+#[derive(Debug, serde::Deserialize)]
+struct RateLimitSettings {
+    global: Option<RateLimit>,
+    per_target: Option<RateLimit>,
+    levels: Option<HashMap<LogLevel, RateLimit>>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    summary_interval: Option<Duration>,
+}
+
+impl RateLimitSettings {
+    fn into_config(self) -> RateLimitConfig {
+        let mut config = RateLimitConfig::default();
+        if let Some(global) = self.global {
+            config = config.with_global(global);
+        }
+        if let Some(per_target) = self.per_target {
+            config = config.with_per_target(per_target);
+        }
+        for (level, limit) in self.levels.unwrap_or_default() {
+            config = config.with_level(level, limit);
+        }
+        if let Some(summary_interval) = self.summary_interval {
+            config = config.with_summary_interval(summary_interval);
+        }
+        config
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SamplingSettings {
+    /// Fraction of records kept by level
+    levels: HashMap<LogLevel, f64>,
+    key_field: Option<String>,
+    adaptive_budget: Option<f64>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    adaptive_window: Option<Duration>,
+}
+
+impl SamplingSettings {
+    fn into_config(self) -> SamplingConfig {
+        let mut config = SamplingConfig::default();
+        for (level, ratio) in self.levels {
+            config = config.with_level(level, ratio);
+        }
+        if let Some(key_field) = self.key_field {
+            config = config.with_key_field(key_field);
+        }
+        if let Some(adaptive_budget) = self.adaptive_budget {
+            config = config.with_adaptive_budget(adaptive_budget);
+        }
+        if let Some(adaptive_window) = self.adaptive_window {
+            config = config.with_adaptive_window(adaptive_window);
+        }
+        config
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DedupSettings {
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    window: Option<Duration>,
+    max_keys: Option<usize>,
+}
+
+impl DedupSettings {
+    fn into_config(self) -> DedupConfig {
+        let mut config = DedupConfig::default();
+        if let Some(window) = self.window {
+            config = config.with_window(window);
+        }
+        if let Some(max_keys) = self.max_keys {
+            config = config.with_max_keys(max_keys);
+        }
+        config
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RedactionSettings {
+    /// Add rules for passwords, tokens, card numbers, emails and JWTs
+    #[serde(default)]
+    common_rules: bool,
+    /// Used by rules without their own replacement
+    #[serde(default)]
+    replacement: Replacement,
+    hash_salt: Option<String>,
+    #[serde(default)]
+    fields: Vec<RedactionRuleSettings>,
+    #[serde(default)]
+    values: Vec<RedactionRuleSettings>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RedactionRuleSettings {
+    pattern: String,
+    replacement: Option<Replacement>,
+}
+
+impl RedactionSettings {
+    fn into_config(self) -> AnyResult<RedactionConfig> {
+        let mut config = RedactionConfig::new();
+        let default = self.replacement;
+        if self.common_rules {
+            config = config.with_common_rules(default.clone());
+        }
+        if let Some(salt) = self.hash_salt {
+            config = config.with_hash_salt(salt);
+        }
+        for rule in self.fields {
+            let replacement = rule.replacement.unwrap_or_else(|| default.clone());
+            config = config.with_field(Regex::new(&rule.pattern)?, replacement);
+        }
+        for rule in self.values {
+            let replacement = rule.replacement.unwrap_or_else(|| default.clone());
+            config = config.with_value(Regex::new(&rule.pattern)?, replacement);
+        }
+        Ok(config)
+    }
+}
+
+/// Built-in codec of sent records
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CodecSettings {
+    JsonLines,
+    JsonArray,
+    Msgpack,
+    Cbor,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LimitsSettings {
+    max_message_len: Option<usize>,
+    max_field_len: Option<usize>,
+    max_fields: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+impl LimitsSettings {
+    fn into_config(self) -> SizeLimits {
+        SizeLimits {
+            max_message_len: self.max_message_len,
+            max_field_len: self.max_field_len,
+            max_fields: self.max_fields,
+            max_depth: self.max_depth,
+        }
+    }
+}
+
+/// Built-in processor, `kind` selects the variant
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ProcessorSettings {
+    AddFields {
+        fields: HashMap<String, Value>,
+        overwrite: Option<bool>,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    RemoveFields {
+        fields: Vec<String>,
+    },
+    Drop {
+        when: ConditionSettings,
+    },
+    Route {
+        when: ConditionSettings,
+        processors: Vec<ProcessorSettings>,
+        #[serde(default)]
+        otherwise: Vec<ProcessorSettings>,
+    },
+}
+
+impl ProcessorSettings {
+    fn into_chain(settings: Vec<ProcessorSettings>) -> ProcessorChain {
+        let mut chain = ProcessorChain::new();
+        for processor in settings {
+            chain.push(processor.into_processor());
+        }
+        chain
+    }
+
+    fn into_processor(self) -> Box<dyn Processor> {
+        match self {
+            ProcessorSettings::AddFields { fields, overwrite } => {
+                Box::new(AddFields::new(fields).with_overwrite(overwrite.unwrap_or(true)))
+            }
+            ProcessorSettings::Rename { from, to } => Box::new(RenameField::new(from, to)),
+            ProcessorSettings::RemoveFields { fields } => Box::new(RemoveFields::new(fields)),
+            ProcessorSettings::Drop { when } => Box::new(DropRecords::new(when.into_condition())),
+            ProcessorSettings::Route {
+                when,
+                processors,
+                otherwise,
+            } => Box::new(
+                Route::new(when.into_condition(), Self::into_chain(processors))
+                    .with_otherwise(Self::into_chain(otherwise)),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConditionSettings {
+    /// Level is this one or more important
+    Level(LogLevel),
+    TargetPrefix(String),
+    FieldExists(String),
+    FieldEquals {
+        field: String,
+        value: Value,
+    },
+    Not(Box<ConditionSettings>),
+    All(Vec<ConditionSettings>),
+    Any(Vec<ConditionSettings>),
+}
+
+impl ConditionSettings {
+    fn into_condition(self) -> Condition {
+        fn all(conditions: Vec<ConditionSettings>) -> Vec<Condition> {
+            conditions
+                .into_iter()
+                .map(ConditionSettings::into_condition)
+                .collect()
+        }
+        match self {
+            ConditionSettings::Level(level) => Condition::Level(level),
+            ConditionSettings::TargetPrefix(prefix) => Condition::TargetPrefix(prefix),
+            ConditionSettings::FieldExists(field) => Condition::FieldExists(field),
+            ConditionSettings::FieldEquals { field, value } => Condition::FieldEquals(field, value),
+            ConditionSettings::Not(condition) => {
+                Condition::Not(Box::new(condition.into_condition()))
+            }
+            ConditionSettings::All(conditions) => Condition::All(all(conditions)),
+            ConditionSettings::Any(conditions) => Condition::Any(all(conditions)),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SpoolSettings {
+    path: PathBuf,
+    segment_size: Option<u64>,
+    max_size: Option<u64>,
+    /// `always`, `never` or sync interval
+    fsync: Option<String>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    retry_interval: Option<Duration>,
+}
+
+impl SpoolSettings {
+    fn into_config(self) -> AnyResult<SpoolConfig> {
+        let mut spool = SpoolConfig::new(self.path);
+        if let Some(segment_size) = self.segment_size {
+            spool = spool.with_segment_size(segment_size);
+        }
+        if let Some(max_size) = self.max_size {
+            spool = spool.with_max_size(max_size);
+        }
+        if let Some(fsync) = self.fsync {
+            let fsync = match fsync.as_str() {
+                "always" => FsyncPolicy::Always,
+                "never" => FsyncPolicy::Never,
+                interval => {
+                    FsyncPolicy::Interval(humantime_serde::re::humantime::parse_duration(interval)?)
+                }
+            };
+            spool = spool.with_fsync(fsync);
+        }
+        if let Some(retry_interval) = self.retry_interval {
+            spool = spool.with_retry_interval(retry_interval);
+        }
+        Ok(spool)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RetrySettings {
+    max_batches: Option<usize>,
+    max_bytes: Option<usize>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    initial_backoff: Option<Duration>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    max_backoff: Option<Duration>,
+    drop_policy: Option<RetryDropPolicy>,
+}
+
+impl RetrySettings {
+    fn into_config(self) -> RetryConfig {
+        let mut retry = RetryConfig::default();
+        if let Some(max_batches) = self.max_batches {
+            retry = retry.with_max_batches(max_batches);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            retry = retry.with_max_bytes(max_bytes);
+        }
+        let initial_backoff = self.initial_backoff.unwrap_or(retry.initial_backoff);
+        let max_backoff = self.max_backoff.unwrap_or(retry.max_backoff);
+        retry = retry.with_backoff(initial_backoff, max_backoff);
+        if let Some(drop_policy) = self.drop_policy {
+            retry = retry.with_drop_policy(drop_policy);
+        }
+        retry
+    }
+}
+
+impl AppenderDeserializer {
+    fn new(extra_fields: Option<HashMap<String, Value>>) -> Self {
         Self {
-            extra_fields: None
+            extra_fields
         }
     }
 }
@@ -69,6 +452,15 @@ impl Deserialize for AppenderDeserializer {
             .with_hostname(&config.hostname)
             .with_port(config.port)
             .with_use_tls(config.use_tls.unwrap_or(false));
+        if let Some(tls_backend) = config.tls_backend {
+            builder = builder.with_tls_backend(tls_backend);
+        }
+        if let Some(proxy) = config.proxy {
+            builder = builder.with_proxy(proxy);
+        }
+        if let Some(compression) = config.compression {
+            builder = builder.with_compression(compression);
+        }
         if let Some(buffer_size) = config.buffer_size {
             builder = builder.with_buffer_size(buffer_size);
         }
@@ -87,6 +479,78 @@ impl Deserialize for AppenderDeserializer {
         if let Some(log_queue_len) = config.log_queue_len {
             builder = builder.with_log_queue_len(log_queue_len);
         }
+        if let Some(overflow_policy) = config.overflow_policy {
+            builder = builder.with_overflow_policy(parse_overflow_policy(&overflow_policy)?);
+        }
+        for (level, policy) in config.overflow_policy_levels.unwrap_or_default() {
+            builder = builder.with_level_overflow_policy(level, parse_overflow_policy(&policy)?);
+        }
+        if let Some(priority_level) = config.priority_level {
+            builder = builder.with_priority_level(priority_level);
+        }
+        if let Some(priority_queue_len) = config.priority_queue_len {
+            builder = builder.with_priority_queue_len(priority_queue_len);
+        }
+        if let Some(max_batch_bytes) = config.max_batch_bytes {
+            builder = builder.with_max_batch_bytes(max_batch_bytes);
+        }
+        if let Some(oversized_policy) = config.oversized_policy {
+            builder = builder.with_oversized_policy(oversized_policy);
+        }
+        if let Some(memory_budget) = config.memory_budget {
+            builder = builder.with_memory_budget(memory_budget);
+        }
+        if let Some(shutdown_timeout) = config.shutdown_timeout {
+            builder = builder.with_shutdown_timeout(shutdown_timeout);
+        }
+        if let Some(restart) = config.restart {
+            builder = builder.with_restart_policy(restart.into_policy());
+        }
+        if let Some(workers) = config.workers {
+            builder = builder.with_workers(workers);
+        }
+        if let Some(worker_ordering) = config.worker_ordering {
+            builder = builder.with_worker_ordering(worker_ordering);
+        }
+        if let Some(rate_limit) = config.rate_limit {
+            builder = builder.with_rate_limit(rate_limit.into_config());
+        }
+        if let Some(sampling) = config.sampling {
+            builder = builder.with_sampling(sampling.into_config());
+        }
+        if let Some(dedup) = config.dedup {
+            builder = builder.with_dedup(dedup.into_config());
+        }
+        if let Some(redaction) = config.redaction {
+            builder = builder.with_redaction(redaction.into_config()?);
+        }
+        let schema = config.schema.unwrap_or_default();
+        builder = match config.codec {
+            Some(CodecSettings::JsonLines) | None => builder.with_schema(schema),
+            Some(CodecSettings::JsonArray) => {
+                builder.with_codec(JsonArrayCodec::new().with_schema(schema))
+            }
+            Some(CodecSettings::Msgpack) => {
+                builder.with_codec(MsgpackCodec::new().with_schema(schema))
+            }
+            Some(CodecSettings::Cbor) => builder.with_codec(CborCodec::new().with_schema(schema)),
+        };
+        if let Some(limits) = config.limits {
+            builder = builder.with_limits(limits.into_config());
+        }
+        #[cfg(feature = "kv")]
+        if let Some(key_values) = config.key_values {
+            builder = builder.with_key_values(key_values);
+        }
+        if let Some(processors) = config.processors {
+            builder = builder.with_processors(ProcessorSettings::into_chain(processors));
+        }
+        if let Some(spool) = config.spool {
+            builder = builder.with_spool(spool.into_config()?);
+        }
+        if let Some(retry) = config.retry {
+            builder = builder.with_retry(retry.into_config());
+        }
 
         let mut extra_fields = self.extra_fields.clone().unwrap_or_default();
         if let Some(config_extra_fields) = config.extra_fields {
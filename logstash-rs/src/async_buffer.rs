@@ -0,0 +1,430 @@
+use log::Level;
+
+use crate::error_handler::{self, ErrorEvent, ErrorHandler, PrintErrorHandler};
+use crate::prelude::*;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_crate::{
+    sync::mpsc::{self, error::TrySendError},
+    sync::oneshot,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+enum Command {
+    Send(LogStashRecord),
+    SendBatch(Vec<LogStashRecord>),
+    /// Send the buffer and flush the connection, the result is sent to the waiting caller
+    Flush(Option<oneshot::Sender<Result<()>>>),
+    /// Send everything and stop the sender task before the deadline
+    Shutdown(Instant, oneshot::Sender<()>),
+}
+
+impl Command {
+    /// Number of records in the command
+    fn records(&self) -> usize {
+        match self {
+            Command::Send(_) => 1,
+            Command::SendBatch(events) => events.len(),
+            Command::Flush(_) | Command::Shutdown(..) => 0,
+        }
+    }
+}
+
+/// Asynchronous counterpart of [`BufferedSender`](crate::BufferedSender).
+///
+/// Records are passed through a bounded channel to a Tokio task which batches them
+/// the same way as the sender thread does. The task sends records left in the channel and
+/// stops when the sender is dropped, as long as the runtime is alive.
+pub struct AsyncBufferedSender {
+    sender: mpsc::Sender<Command>,
+    /// Number of records in the channel
+    queued: Arc<AtomicUsize>,
+    /// Number of records in the buffer of the sender task
+    held: Arc<AtomicUsize>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    metrics: Metrics,
+}
+
+impl AsyncBufferedSender {
+    pub fn builder<S: AsyncSender>(sender: S) -> AsyncBufferedSenderBuilder<S> {
+        AsyncBufferedSenderBuilder::new(sender)
+    }
+
+    /// Enqueues the record without waiting, fails with [`Error::BufferFull`] if the channel
+    /// is full. Allows logging from synchronous code.
+    pub fn try_send(&self, event: LogStashRecord) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(Command::Send(event)) {
+            Ok(()) => {
+                self.metrics.add_enqueued(1);
+                Ok(())
+            }
+            Err(err) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                match err {
+                    TrySendError::Full(_) => {
+                        self.metrics.add_dropped(DropReason::QueueFull, 1);
+                        Err(Error::BufferFull())
+                    }
+                    TrySendError::Closed(_) => Err(stopped()),
+                }
+            }
+        }
+    }
+
+    /// Current metrics including ones collected by the wrapped sender if it shares the handle
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let mut snapshot = self.metrics.snapshot();
+        snapshot.queue_depth = self.queued.load(Ordering::Relaxed);
+        snapshot
+    }
+
+    async fn push(&self, command: Command) -> Result<()> {
+        let records = command.records();
+        // Counted before sending, the task may receive the command immediately
+        self.queued.fetch_add(records, Ordering::Relaxed);
+        if self.sender.send(command).await.is_err() {
+            self.queued.fetch_sub(records, Ordering::Relaxed);
+            return Err(stopped());
+        }
+        self.metrics.add_enqueued(records);
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.queued.load(Ordering::Relaxed) + self.held.load(Ordering::Relaxed)
+    }
+}
+
+fn stopped() -> Error {
+    Error::SenderThreadStopped("sender task stopped".to_string())
+}
+
+#[async_trait::async_trait]
+impl AsyncSender for AsyncBufferedSender {
+    /// Waits for free space in the channel if it is full
+    async fn send(&self, event: LogStashRecord) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        self.push(Command::Send(event)).await
+    }
+
+    async fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()> {
+        if events.is_empty() || error_handler::is_reporting() {
+            return Ok(());
+        }
+        self.push(Command::SendBatch(events)).await
+    }
+
+    /// Waits until records enqueued before the call are sent and the connection is flushed
+    async fn flush(&self) -> Result<()> {
+        let (reply, done) = oneshot::channel();
+        self.push(Command::Flush(Some(reply))).await?;
+        done.await.map_err(|_| stopped())?
+    }
+
+    /// Sends all records and stops the sender task, records sent after it are rejected.
+    async fn shutdown(&self, timeout: Duration) -> Result<()> {
+        let task = self.task.lock()?.take();
+        let task = match task {
+            Some(task) => task,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + timeout;
+        let (reply, done) = oneshot::channel();
+        let command = Command::Shutdown(deadline, reply);
+        match time::timeout_at(deadline, self.sender.send(command)).await {
+            Err(_) => return Err(Error::ShutdownTimeout(self.pending())),
+            Ok(Err(_)) => return Err(stopped()),
+            Ok(Ok(())) => {}
+        }
+        if time::timeout_at(deadline, done).await.is_err() {
+            // The task is left to finish in background
+            return Err(Error::ShutdownTimeout(self.pending()));
+        }
+        let _ = task.await;
+        match self.pending() {
+            0 => Ok(()),
+            pending => Err(Error::ShutdownTimeout(pending)),
+        }
+    }
+}
+
+pub struct AsyncBufferedSenderBuilder<S> {
+    sender: S,
+    buffer_size: Option<usize>,
+    buffer_lifetime: Option<Duration>,
+    ignore_buffer: Level,
+    error_period: Duration,
+    log_queue_len: usize,
+    metrics: Metrics,
+    error_handler: Option<Arc<dyn ErrorHandler>>,
+}
+
+impl<S: AsyncSender> AsyncBufferedSenderBuilder<S> {
+    fn new(sender: S) -> Self {
+        Self {
+            sender,
+            buffer_size: Some(100),
+            buffer_lifetime: Some(Duration::from_secs(1)),
+            ignore_buffer: Level::Error,
+            error_period: Duration::from_secs(10),
+            log_queue_len: 1000,
+            metrics: Metrics::default(),
+            error_handler: None,
+        }
+    }
+
+    /// Sets the maximum number of records in the buffer, `None` disables buffering.
+    pub fn with_buffer_size(mut self, buffer_size: Option<usize>) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Sets the maximum lifetime of the buffer before it is sent.
+    pub fn with_buffer_lifetime(mut self, buffer_lifetime: Option<Duration>) -> Self {
+        self.buffer_lifetime = buffer_lifetime;
+        self
+    }
+
+    /// Records with this level or less important are sent bypassing the buffer.
+    pub fn with_ignore_buffer_level(mut self, level: Level) -> Self {
+        self.ignore_buffer = level;
+        self
+    }
+
+    /// Print period for internal errors, used by default error handler.
+    pub fn with_error_period(mut self, error_period: Duration) -> Self {
+        self.error_period = error_period;
+        self
+    }
+
+    /// Receives internal errors instead of printing them to stderr.
+    pub fn with_error_handler(mut self, handler: impl ErrorHandler) -> Self {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Maximum length of the channel between logging tasks and the sender task.
+    pub fn with_log_queue_len(mut self, log_queue_len: usize) -> Self {
+        self.log_queue_len = log_queue_len;
+        self
+    }
+
+    /// Collects metrics into the handle, it may be shared with the wrapped sender.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Spawns the sender task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn build(self) -> AsyncBufferedSender {
+        let (sender, receiver) = mpsc::channel(self.log_queue_len.max(1));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let held = Arc::new(AtomicUsize::new(0));
+        let error_period = self.error_period;
+        let task = AsyncBufferedSenderTask {
+            sender: self.sender,
+            buffer: Vec::with_capacity(self.buffer_size.unwrap_or(0)),
+            buffer_size: self.buffer_size,
+            buffer_lifetime: self.buffer_lifetime,
+            deadline: None,
+            ignore_buffer: self.ignore_buffer,
+            error_handler: self
+                .error_handler
+                .unwrap_or_else(|| Arc::new(PrintErrorHandler::new(error_period))),
+            queued: queued.clone(),
+            held: held.clone(),
+            metrics: self.metrics.clone(),
+        };
+        AsyncBufferedSender {
+            sender,
+            queued,
+            held,
+            task: Mutex::new(Some(tokio_crate::spawn(task.run(receiver)))),
+            metrics: self.metrics,
+        }
+    }
+}
+
+struct AsyncBufferedSenderTask<S: AsyncSender> {
+    sender: S,
+    buffer: Vec<LogStashRecord>,
+    buffer_size: Option<usize>,
+    buffer_lifetime: Option<Duration>,
+    deadline: Option<Instant>,
+    ignore_buffer: Level,
+    error_handler: Arc<dyn ErrorHandler>,
+    queued: Arc<AtomicUsize>,
+    held: Arc<AtomicUsize>,
+    metrics: Metrics,
+}
+
+impl<S: AsyncSender> AsyncBufferedSenderTask<S> {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Command>) {
+        loop {
+            let command = match self.deadline {
+                Some(deadline) => match time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(command) => command,
+                    Err(_) => {
+                        let dropped_before = self.metrics.dropped_total();
+                        let result = self.flush().await;
+                        self.report(result, dropped_before);
+                        continue;
+                    }
+                },
+                None => receiver.recv().await,
+            };
+            let dropped_before = self.metrics.dropped_total();
+            match command {
+                Some(Command::Shutdown(deadline, reply)) => {
+                    receiver.close();
+                    // Records left after the deadline are abandoned with the task
+                    let _ =
+                        time::timeout_at(deadline, self.shutdown(&mut receiver, deadline)).await;
+                    let _ = reply.send(());
+                    return;
+                }
+                Some(Command::Flush(Some(reply))) => {
+                    let result = self.flush().await;
+                    if let Err(err) = &result {
+                        self.metrics.add_error(err);
+                    }
+                    let _ = reply.send(result);
+                }
+                Some(command) => {
+                    let result = self.process(command).await;
+                    self.report(result, dropped_before);
+                }
+                // All senders are dropped, buffered records are still sent
+                None => {
+                    let result = self.flush().await;
+                    self.report(result, dropped_before);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn process(&mut self, command: Command) -> Result<()> {
+        self.queued.fetch_sub(command.records(), Ordering::Relaxed);
+        match command {
+            Command::Send(event) => {
+                self.deadline = self.next_deadline();
+                self.send(event).await
+            }
+            Command::SendBatch(events) => {
+                self.deadline = self.next_deadline();
+                // Records go through the buffer to keep the order with buffered ones
+                let mut events = events.into_iter();
+                while let Some(event) = events.next() {
+                    if let Err(err) = self.send(event).await {
+                        self.metrics
+                            .add_dropped(DropReason::SendFailed, events.len());
+                        return Err(err);
+                    }
+                }
+                Ok(())
+            }
+            Command::Flush(_) => self.flush().await,
+            // Only one shutdown is possible
+            Command::Shutdown(..) => Ok(()),
+        }
+    }
+
+    /// Sends records left in the channel and the buffer
+    async fn shutdown(&mut self, receiver: &mut mpsc::Receiver<Command>, deadline: Instant) {
+        while let Some(command) = receiver.recv().await {
+            let dropped_before = self.metrics.dropped_total();
+            let result = self.process(command).await;
+            self.report(result, dropped_before);
+        }
+        let dropped_before = self.metrics.dropped_total();
+        let result = self.flush().await;
+        self.report(result, dropped_before);
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let result = self.sender.shutdown(timeout).await;
+        self.report(result, dropped_before);
+    }
+
+    fn report(&self, result: Result<()>, dropped_before: u64) {
+        if let Err(err) = result {
+            self.metrics.add_error(&err);
+            let dropped = self.metrics.dropped_total().saturating_sub(dropped_before);
+            error_handler::report(&*self.error_handler, &ErrorEvent::new(err, dropped));
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        if self.buffer_size.is_some() {
+            return self
+                .deadline
+                .or_else(|| self.buffer_lifetime.map(|lt| Instant::now() + lt));
+        }
+        None
+    }
+
+    async fn send(&mut self, event: LogStashRecord) -> Result<()> {
+        match self.buffer_size {
+            Some(max_size) if event.level < self.ignore_buffer => {
+                self.buffer.push(event);
+                self.held.store(self.buffer.len(), Ordering::Relaxed);
+                if self.buffer.len() >= max_size {
+                    self.flush().await?;
+                }
+                Ok(())
+            }
+            _ => {
+                let result = self.sender.send(event).await;
+                if result.is_err() {
+                    self.metrics.add_dropped(DropReason::SendFailed, 1);
+                }
+                result
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.deadline = None;
+        if !self.buffer.is_empty() {
+            let events = std::mem::replace(
+                &mut self.buffer,
+                Vec::with_capacity(self.buffer_size.unwrap_or(0)),
+            );
+            let len = events.len();
+            self.held.store(0, Ordering::Relaxed);
+            if let Err(err) = self.sender.send_batch(events).await {
+                self.metrics.add_dropped(DropReason::SendFailed, len);
+                return Err(err);
+            }
+        }
+        self.sender.flush().await
+    }
+}
+
+impl log::Log for AsyncBufferedSender {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let record = LogStashRecord::from_record(record);
+        let _ = self.try_send(record);
+    }
+
+    fn flush(&self) {
+        let _ = self.sender.try_send(Command::Flush(None));
+    }
+}
@@ -1,20 +1,166 @@
 use log::Level;
 
+use crate::dedup::{Dedup, DedupConfig, Dedupe};
+use crate::error_handler::{self, ErrorEvent, ErrorHandler, PrintErrorHandler};
+use crate::limits::{add_tag, SizeLimits, TRUNCATED_TAG, TRUNCATION_MARKER};
+use crate::metrics::MetricsReporter;
 use crate::prelude::*;
+use crate::queue::{
+    mark_delivering_thread, queue, OverflowPolicies, OverflowPolicy, QueueBudget, QueueReceiver,
+    QueueSender,
+};
+use crate::rate_limit::{RateLimitConfig, RateLimiter};
+use crate::retry::{RetryConfig, RetryQueue};
+use crate::sampling::{Sampler, SamplingConfig};
+use crate::spool::{Spool, SpoolConfig, SpoolPosition};
+use serde_json::Value;
 use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicUsize, Ordering},
     sync::mpsc::{self, TrySendError},
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
+/// What to do with a record which alone exceeds batch size limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedPolicy {
+    /// Cut the `message` field to fit the limit, drop the record if it is not enough
+    Truncate,
+    /// Drop the record
+    Drop,
+    /// Send the record in a separate batch
+    #[default]
+    SendAlone,
+}
+
+/// Limits restarts of the sender thread after fatal errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Maximum number of restarts, unlimited if `None`
+    pub max_restarts: Option<usize>,
+    /// Delay before each restart
+    pub backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: None,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn with_max_restarts(mut self, max_restarts: usize) -> Self {
+        self.max_restarts = Some(max_restarts);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum Command {
     Send(LogStashRecord),
     SendBatch(Vec<LogStashRecord>),
     Flush,
+    /// Flush which signals the ticket once all previous records are delivered
+    FlushBlocking(Arc<FlushTicket>),
+    /// Send everything and stop the sender thread, failed batches are retried until the deadline
+    Shutdown(Arc<FlushTicket>, Instant),
+}
+
+/// Result of blocking flush or shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlushOutcome {
+    Delivered,
+    /// Flush failed or the number of records was dropped
+    Failed(usize),
+    /// Sender thread stopped
+    Stopped,
+}
+
+/// Completion signal of blocking flush or shutdown
+#[derive(Debug, Default)]
+pub(crate) struct FlushTicket {
+    done: Mutex<Option<FlushOutcome>>,
+    cond: Condvar,
+}
+
+impl FlushTicket {
+    fn complete(&self, outcome: FlushOutcome) {
+        let mut done = self.done.lock().unwrap_or_else(|e| e.into_inner());
+        done.get_or_insert(outcome);
+        self.cond.notify_all();
+    }
+
+    /// Returns `None` if not completed before the timeout
+    fn wait(&self, timeout: Duration) -> Option<FlushOutcome> {
+        let done = self.done.lock().unwrap_or_else(|e| e.into_inner());
+        let (done, _) = self
+            .cond
+            .wait_timeout_while(done, timeout, |done| done.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        *done
+    }
+}
+
+impl Command {
+    /// The most important level of the records
+    pub(crate) fn level(&self) -> Option<Level> {
+        match self {
+            Command::Send(event) => Some(event.level),
+            Command::SendBatch(events) => events.iter().map(|e| e.level).min(),
+            Command::Flush | Command::FlushBlocking(_) | Command::Shutdown(..) => None,
+        }
+    }
+
+    pub(crate) fn serialized_len(&self) -> usize {
+        match self {
+            Command::Send(event) => event.serialized_len(),
+            Command::SendBatch(events) => events.iter().map(|e| e.serialized_len()).sum(),
+            Command::Flush | Command::FlushBlocking(_) | Command::Shutdown(..) => 0,
+        }
+    }
+
+    /// Number of records in the command
+    pub(crate) fn records(&self) -> usize {
+        match self {
+            Command::Send(_) => 1,
+            Command::SendBatch(events) => events.len(),
+            Command::Flush | Command::FlushBlocking(_) | Command::Shutdown(..) => 0,
+        }
+    }
+
+    pub(crate) fn is_record(&self) -> bool {
+        matches!(self, Command::Send(_) | Command::SendBatch(_))
+    }
+
+    /// Contains records with `Warn` level or more important
+    pub(crate) fn is_important(&self) -> bool {
+        self.level().map(|l| l <= Level::Warn).unwrap_or(false)
+    }
 }
 
 pub struct BufferedSender {
-    sender: mpsc::SyncSender<Command>,
+    sender: QueueSender,
+    overflow: OverflowPolicies,
+    priority_level: Option<Level>,
+    /// Number of records held by the sender thread in buffer, retry queue and spool
+    held: Arc<AtomicUsize>,
+    thread: Mutex<Option<JoinHandle<Result<()>>>>,
+    metrics: Metrics,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    sampler: Option<Sampler>,
+    redactor: Option<Redactor>,
+    limits: Option<SizeLimits>,
 }
 
 impl BufferedSender {
@@ -26,49 +172,477 @@ impl BufferedSender {
         error_period: Duration,
         log_queue_len: usize,
     ) -> Self {
-        let sender = BufferedSenderThread::new(
+        let thread = BufferedSenderThread::new(
             sender,
             buffer_size,
             buffer_lifetime,
             ignore_buffer,
             error_period,
             log_queue_len,
-        )
-        .run();
-        Self { sender }
+        );
+        let held = thread.held.clone();
+        let metrics = thread.metrics.clone();
+        let (sender, thread) = thread.run();
+        Self {
+            sender,
+            overflow: OverflowPolicies::default(),
+            priority_level: None,
+            held,
+            thread: Mutex::new(Some(thread)),
+            metrics,
+            rate_limiter: None,
+            sampler: None,
+            redactor: None,
+            limits: None,
+        }
+    }
+
+    pub fn builder<S: Sender>(sender: S) -> BufferedSenderBuilder<S> {
+        BufferedSenderBuilder::new(sender)
+    }
+}
+
+pub struct BufferedSenderBuilder<S> {
+    sender: S,
+    buffer_size: Option<usize>,
+    buffer_lifetime: Option<Duration>,
+    ignore_buffer: Level,
+    error_period: Duration,
+    log_queue_len: usize,
+    spool: Option<SpoolConfig>,
+    retry: Option<RetryConfig>,
+    overflow: OverflowPolicies,
+    priority: Option<(Level, usize)>,
+    max_batch_bytes: Option<usize>,
+    oversized_policy: OversizedPolicy,
+    memory_budget: Option<usize>,
+    metrics: Metrics,
+    reporter: Option<MetricsReporter>,
+    error_handler: Option<Arc<dyn ErrorHandler>>,
+    factory: Option<SenderFactory<S>>,
+    restart: RestartPolicy,
+    rate_limit: Option<RateLimitConfig>,
+    sampling: Option<SamplingConfig>,
+    dedup: Option<DedupConfig>,
+    redaction: Option<RedactionConfig>,
+    limits: Option<SizeLimits>,
+}
+
+impl<S: Sender> BufferedSenderBuilder<S> {
+    fn new(sender: S) -> Self {
+        Self {
+            sender,
+            buffer_size: Some(100),
+            buffer_lifetime: Some(Duration::from_secs(1)),
+            ignore_buffer: Level::Error,
+            error_period: Duration::from_secs(10),
+            log_queue_len: 1000,
+            spool: None,
+            retry: None,
+            overflow: OverflowPolicies::default(),
+            priority: None,
+            max_batch_bytes: None,
+            oversized_policy: OversizedPolicy::default(),
+            memory_budget: None,
+            metrics: Metrics::default(),
+            reporter: None,
+            error_handler: None,
+            factory: None,
+            restart: RestartPolicy::default(),
+            rate_limit: None,
+            sampling: None,
+            dedup: None,
+            redaction: None,
+            limits: None,
+        }
+    }
+
+    /// Sets the maximum number of records in the buffer, `None` disables buffering.
+    pub fn with_buffer_size(mut self, buffer_size: Option<usize>) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Sets the maximum lifetime of the buffer before it is sent.
+    pub fn with_buffer_lifetime(mut self, buffer_lifetime: Option<Duration>) -> Self {
+        self.buffer_lifetime = buffer_lifetime;
+        self
+    }
+
+    /// Records with this level or less important are sent bypassing the buffer.
+    pub fn with_ignore_buffer_level(mut self, level: Level) -> Self {
+        self.ignore_buffer = level;
+        self
+    }
+
+    /// Print period for internal errors, used by default error handler.
+    pub fn with_error_period(mut self, error_period: Duration) -> Self {
+        self.error_period = error_period;
+        self
+    }
+
+    /// Receives internal errors instead of printing them to stderr.
+    pub fn with_error_handler(mut self, handler: impl ErrorHandler) -> Self {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Maximum length of the queue between logging threads and the sender thread.
+    pub fn with_log_queue_len(mut self, log_queue_len: usize) -> Self {
+        self.log_queue_len = log_queue_len;
+        self
+    }
+
+    /// Sets the maximum serialized size of the batch in bytes.
+    ///
+    /// Sizes are measured as plain JSON of the records, so they are estimates for other codecs
+    /// and ECS schema.
+    pub fn with_max_batch_bytes(mut self, max_batch_bytes: usize) -> Self {
+        self.max_batch_bytes = Some(max_batch_bytes);
+        self
+    }
+
+    /// Sets what to do with a record larger than `max_batch_bytes`.
+    pub fn with_oversized_policy(mut self, policy: OversizedPolicy) -> Self {
+        self.oversized_policy = policy;
+        self
+    }
+
+    /// Limits serialized size of records in the log queue and the buffer, records exceeding
+    /// the budget are handled by overflow policy. Records of the priority lane are counted but
+    /// never rejected by the budget. Sizes are estimated as plain JSON of the records.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Collects metrics into the handle, it may be shared with the wrapped sender.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Calls `callback` with metrics snapshot every `interval` from the sender thread.
+    pub fn with_metrics_callback(
+        mut self,
+        interval: Duration,
+        callback: impl Fn(&MetricsSnapshot) + Send + Sync + 'static,
+    ) -> Self {
+        self.reporter = Some(MetricsReporter {
+            interval,
+            callback: Box::new(callback),
+        });
+        self
+    }
+
+    /// Restarts the sender thread with a sender created by `factory` after fatal error or
+    /// panic. Records in the queue, the buffer and retry queue are kept.
+    pub fn with_restart(
+        mut self,
+        policy: RestartPolicy,
+        factory: impl Fn() -> Result<S> + Send + 'static,
+    ) -> Self {
+        self.restart = policy;
+        self.factory = Some(Box::new(factory));
+        self
+    }
+
+    /// Throttles records before they enter the log queue.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Keeps a fraction of records with sampled levels, applied before rate limiting.
+    pub fn with_sampling(mut self, sampling: SamplingConfig) -> Self {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    /// Masks or hashes sensitive values before records enter the log queue.
+    pub fn with_redaction(mut self, redaction: RedactionConfig) -> Self {
+        self.redaction = Some(redaction);
+        self
+    }
+
+    /// Truncates records exceeding the limits before they enter the log queue, applied after
+    /// redaction.
+    pub fn with_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Collapses records with the same template received within a window into one.
+    pub fn with_dedup(mut self, dedup: DedupConfig) -> Self {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Persist records in on-disk spool until they are sent.
+    pub fn with_spool(mut self, spool: SpoolConfig) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Sets what to do with records when the log queue is full, `DropNewest` by default.
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow.set_all(policy);
+        self
+    }
+
+    /// Overrides overflow policy for records with the `level`.
+    pub fn with_level_overflow_policy(mut self, level: Level, policy: OverflowPolicy) -> Self {
+        self.overflow.set(level, policy);
+        self
+    }
+
+    /// Puts records with `level` or more important to separate queue of `queue_len` length,
+    /// which is always processed before other records. Other records never evict or block
+    /// records in this queue.
+    pub fn with_priority_lane(mut self, level: Level, queue_len: usize) -> Self {
+        self.priority = Some((level, queue_len));
+        self
+    }
+
+    /// Keep failed batches in memory and retry them before newer records.
+    /// Not used together with spool which retries records itself.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Starts the sender thread.
+    pub fn build(self) -> Result<BufferedSender> {
+        let (spool, locked_spool) = match self.spool.map(|c| (Spool::open(c.clone()), c)) {
+            Some((Ok(spool), _)) => (Some(spool), None),
+            // Opened by the sender thread once the other instance releases it
+            Some((Err(Error::SpoolLocked(_)), config)) => (None, Some(config)),
+            Some((Err(err), _)) => return Err(err),
+            None => (None, None),
+        };
+        let mut thread = BufferedSenderThread::new(
+            self.sender,
+            self.buffer_size,
+            self.buffer_lifetime,
+            self.ignore_buffer,
+            self.error_period,
+            self.log_queue_len,
+        );
+        thread.spool = spool;
+        thread.locked_spool = locked_spool;
+        thread.retry = self.retry.map(RetryQueue::new);
+        thread.priority = self.priority;
+        thread.max_batch_bytes = self.max_batch_bytes;
+        thread.oversized_policy = self.oversized_policy;
+        thread.memory_budget = self.memory_budget;
+        thread.metrics = self.metrics;
+        thread.reporter = self.reporter;
+        if let Some(error_handler) = self.error_handler {
+            thread.error_handler = error_handler;
+        }
+        thread.factory = self.factory;
+        thread.restart = self.restart;
+        thread.dedup = self.dedup.map(Dedup::new);
+        let rate_limiter = self
+            .rate_limit
+            .map(|config| Arc::new(RateLimiter::new(config)));
+        thread.rate_limiter = rate_limiter.clone();
+        let held = thread.held.clone();
+        let metrics = thread.metrics.clone();
+        let (sender, thread) = thread.run();
+        Ok(BufferedSender {
+            sender,
+            overflow: self.overflow,
+            priority_level: self.priority.map(|(level, _)| level),
+            held,
+            thread: Mutex::new(Some(thread)),
+            metrics,
+            rate_limiter,
+            sampler: self.sampling.map(Sampler::new),
+            redactor: self.redaction.map(Redactor::new),
+            limits: self.limits,
+        })
     }
 }
 
 impl Sender for BufferedSender {
-    fn send(&self, event: LogStashRecord) -> Result<()> {
-        let important = event.level <= Level::Warn;
-        let result = self.sender.try_send(Command::Send(event));
-        process_result(result, important)
+    fn send(&self, mut event: LogStashRecord) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        if !self.is_allowed(&mut event) {
+            return Ok(());
+        }
+        if let Some(redactor) = &self.redactor {
+            redactor.redact(&mut event);
+        }
+        if let Some(limits) = &self.limits {
+            limits.apply(&mut event);
+        }
+        let policy = self.overflow.get(event.level);
+        self.push(Command::Send(event), policy)
     }
 
-    fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()> {
-        let important = events.iter().any(|e| e.level <= Level::Warn);
-        let result = self.sender.try_send(Command::SendBatch(events));
-        process_result(result, important)
+    fn send_batch(&self, mut events: Vec<LogStashRecord>) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        if self.rate_limiter.is_some() || self.sampler.is_some() {
+            events.retain_mut(|event| self.is_allowed(event));
+        }
+        if let Some(redactor) = &self.redactor {
+            events.iter_mut().for_each(|event| redactor.redact(event));
+        }
+        if let Some(limits) = &self.limits {
+            events.iter_mut().for_each(|event| {
+                limits.apply(event);
+            });
+        }
+        if let Some(priority_level) = self.priority_level {
+            // Each part goes to its own lane
+            let (high, normal): (Vec<_>, Vec<_>) =
+                events.into_iter().partition(|e| e.level <= priority_level);
+            let high = self.push_batch(high);
+            let normal = self.push_batch(normal);
+            return high.and(normal);
+        }
+        self.push_batch(events)
     }
 
     fn flush(&self) -> Result<()> {
-        let result = self.sender.try_send(Command::Flush);
+        let result = self.sender.push(Command::Flush, OverflowPolicy::DropNewest);
         process_result(result, false)
     }
+
+    /// Sends all records and stops the sender thread, records sent after it are rejected.
+    /// Failed batches kept for retry are retried until the timeout.
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        let thread = match self.thread.lock()?.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + timeout;
+        let ticket = Arc::new(FlushTicket::default());
+        let command = Command::Shutdown(ticket.clone(), deadline);
+        match self.sender.push(command, OverflowPolicy::Block(timeout)) {
+            Err(err @ TrySendError::Disconnected(..)) => {
+                let _ = thread.join();
+                return Err(Error::SenderThreadStopped(err.to_string()));
+            }
+            Err(TrySendError::Full(..)) => return Err(Error::ShutdownTimeout(self.pending())),
+            Ok(_) => {}
+        }
+        if ticket
+            .wait(deadline.saturating_duration_since(Instant::now()))
+            .is_none()
+        {
+            // The thread is left to finish in background
+            return Err(Error::ShutdownTimeout(self.pending()));
+        }
+        let _ = thread.join();
+        match self.pending() {
+            0 => Ok(()),
+            pending => Err(Error::ShutdownTimeout(pending)),
+        }
+    }
 }
 
-fn process_result<T>(r: std::result::Result<(), TrySendError<T>>, log_full: bool) -> Result<()> {
+impl BufferedSender {
+    /// Waits until the sender thread has sent and flushed all records enqueued before the call.
+    /// Records which failed to send are waited for only if they are kept for retry or in spool.
+    ///
+    /// Returns number of records still pending if the timeout expires, `0` otherwise.
+    /// Fails with [`Error::FlushFailed`] if the flush failed or records were dropped by the
+    /// sender thread since the previous blocking flush.
+    pub fn flush_blocking(&self, timeout: Duration) -> Result<usize> {
+        let deadline = Instant::now() + timeout;
+        let ticket = Arc::new(FlushTicket::default());
+        let command = Command::FlushBlocking(ticket.clone());
+        match self.sender.push(command, OverflowPolicy::Block(timeout)) {
+            Err(err @ TrySendError::Disconnected(..)) => {
+                return Err(Error::SenderThreadStopped(err.to_string()))
+            }
+            Err(TrySendError::Full(..)) => return Ok(self.pending()),
+            Ok(_) => {}
+        }
+        match ticket.wait(deadline.saturating_duration_since(Instant::now())) {
+            Some(FlushOutcome::Delivered) => Ok(0),
+            Some(FlushOutcome::Failed(dropped)) => Err(Error::FlushFailed(dropped)),
+            Some(FlushOutcome::Stopped) => Err(Error::SenderThreadStopped(
+                "sender thread stopped before flush".to_string(),
+            )),
+            None => Ok(self.pending()),
+        }
+    }
+
+    /// Current metrics including ones collected by the wrapped sender if it shares the handle
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let mut snapshot = self.metrics.snapshot();
+        snapshot.queue_depth = self.sender.records();
+        snapshot
+    }
+
+    /// Number of records not yet delivered
+    fn pending(&self) -> usize {
+        self.sender.records() + self.held.load(Ordering::Relaxed)
+    }
+
+    /// Sampled out and suppressed records are counted and dropped silently
+    fn is_allowed(&self, event: &mut LogStashRecord) -> bool {
+        if let Some(sampler) = &self.sampler {
+            if !sampler.sample(event) {
+                self.metrics.add_dropped(DropReason::Sampled, 1);
+                return false;
+            }
+        }
+        match &self.rate_limiter {
+            Some(limiter) if !limiter.check(event) => {
+                self.metrics.add_dropped(DropReason::RateLimited, 1);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn push_batch(&self, events: Vec<LogStashRecord>) -> Result<()> {
+        let level = match events.iter().map(|e| e.level).min() {
+            Some(level) => level,
+            None => return Ok(()),
+        };
+        self.push(Command::SendBatch(events), self.overflow.get(level))
+    }
+
+    fn push(&self, command: Command, policy: OverflowPolicy) -> Result<()> {
+        let records = command.records();
+        let important = command.is_important();
+        let result = self.sender.push(command, policy);
+        match &result {
+            Ok(evicted) => {
+                self.metrics.add_enqueued(records);
+                let evicted = evicted.iter().map(Command::records).sum();
+                self.metrics.add_dropped(DropReason::Evicted, evicted);
+            }
+            Err(TrySendError::Full(..)) => self.metrics.add_dropped(DropReason::QueueFull, records),
+            Err(TrySendError::Disconnected(..)) => {}
+        }
+        process_result(result, important)
+    }
+}
+
+fn process_result(
+    r: std::result::Result<Vec<Command>, TrySendError<()>>,
+    log_full: bool,
+) -> Result<()> {
     match r {
         Err(TrySendError::Disconnected(..)) => {
             Err(Error::SenderThreadStopped(r.unwrap_err().to_string()))
         }
         Err(TrySendError::Full(..)) if log_full => Err(Error::BufferFull()),
+        Ok(evicted) if evicted.iter().any(Command::is_important) => Err(Error::BufferFull()),
         _ => Ok(()),
     }
 }
 
-#[derive(Debug)]
 struct BufferedSenderThread<S: Sender> {
     sender: S,
     buffer: Vec<LogStashRecord>,
@@ -76,8 +650,103 @@ struct BufferedSenderThread<S: Sender> {
     buffer_lifetime: Option<Duration>,
     deadline: Option<Instant>,
     ignore_buffer: Level,
-    error_period: Duration,
+    error_handler: Arc<dyn ErrorHandler>,
     log_queue_len: usize,
+    spool: Option<Spool>,
+    /// Spool used by another instance, e.g. appender replaced by config reload
+    locked_spool: Option<SpoolConfig>,
+    retry_at: Option<Instant>,
+    retry: Option<RetryQueue>,
+    priority: Option<(Level, usize)>,
+    max_batch_bytes: Option<usize>,
+    oversized_policy: OversizedPolicy,
+    memory_budget: Option<usize>,
+    budget: Option<QueueBudget>,
+    buffer_bytes: usize,
+    held: Arc<AtomicUsize>,
+    /// Blocking flushes waiting for delivery of records kept for retry or in spool
+    waiting: Vec<PendingFlush>,
+    stopping: Option<Arc<FlushTicket>>,
+    /// Records dropped by the thread after they were accepted
+    lost: usize,
+    /// Value of `lost` at the previous blocking flush
+    lost_mark: usize,
+    metrics: Metrics,
+    reporter: Option<MetricsReporter>,
+    report_at: Option<Instant>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    dedup: Option<Dedup>,
+    factory: Option<SenderFactory<S>>,
+    restart: RestartPolicy,
+    restarts: usize,
+}
+
+type SenderFactory<S> = Box<dyn Fn() -> Result<S> + Send>;
+
+#[derive(Debug)]
+struct PendingFlush {
+    ticket: Arc<FlushTicket>,
+    retry_mark: u64,
+    spool_mark: Option<SpoolPosition>,
+    lost_mark: usize,
+    /// Flush failed without retry or spool
+    failed: bool,
+}
+
+impl<S: Sender> Drop for BufferedSenderThread<S> {
+    fn drop(&mut self) {
+        // Wake up everyone waiting for the stopped thread
+        for flush in self.waiting.drain(..) {
+            flush.ticket.complete(FlushOutcome::Stopped);
+        }
+        if let Some(ticket) = self.stopping.take() {
+            ticket.complete(FlushOutcome::Delivered);
+        }
+    }
+}
+
+/// Number of spooled records sent at once when buffering is disabled
+const SPOOL_READ_BATCH: usize = 100;
+
+/// Cuts the `message` field on a char boundary so the tagged record fits `max_bytes`,
+/// returns the new size or `None` if other fields alone exceed the limit
+fn truncate_message(event: &mut LogStashRecord, max_bytes: usize) -> Option<usize> {
+    let message_len = match event.fields.get("message") {
+        Some(Value::String(message)) => json_string_len(message),
+        _ => return None,
+    };
+    add_tag(event, TRUNCATED_TAG);
+    let other_len = event.serialized_len() - message_len;
+    // Quotes and the marker are added to the cut message
+    let budget = max_bytes.checked_sub(other_len + 2 + TRUNCATION_MARKER.len())?;
+    if let Some(Value::String(message)) = event.fields.get_mut("message") {
+        let mut len = 0;
+        let mut end = 0;
+        for (i, c) in message.char_indices() {
+            len += json_char_len(c);
+            if len > budget {
+                break;
+            }
+            end = i + c.len_utf8();
+        }
+        message.truncate(end);
+        message.push_str(TRUNCATION_MARKER);
+    }
+    Some(event.serialized_len()).filter(|size| *size <= max_bytes)
+}
+
+/// Length of the string serialized as JSON, including quotes
+fn json_string_len(s: &str) -> usize {
+    2 + s.chars().map(json_char_len).sum::<usize>()
+}
+
+/// Length of the character escaped as in JSON strings
+fn json_char_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\u{8}' | '\t' | '\n' | '\u{c}' | '\r' => 2,
+        c if c < ' ' => 6,
+        c => c.len_utf8(),
+    }
 }
 
 impl<S: Sender> BufferedSenderThread<S> {
@@ -96,103 +765,565 @@ impl<S: Sender> BufferedSenderThread<S> {
             buffer_lifetime,
             deadline: None,
             ignore_buffer,
-            error_period,
+            error_handler: Arc::new(PrintErrorHandler::new(error_period)),
             log_queue_len,
+            spool: None,
+            locked_spool: None,
+            retry_at: None,
+            retry: None,
+            priority: None,
+            max_batch_bytes: None,
+            oversized_policy: OversizedPolicy::default(),
+            memory_budget: None,
+            budget: None,
+            buffer_bytes: 0,
+            held: Arc::new(AtomicUsize::new(0)),
+            waiting: vec![],
+            stopping: None,
+            lost: 0,
+            lost_mark: 0,
+            metrics: Metrics::default(),
+            reporter: None,
+            report_at: None,
+            rate_limiter: None,
+            dedup: None,
+            factory: None,
+            restart: RestartPolicy::default(),
+            restarts: 0,
         }
     }
 
-    fn run(self) -> mpsc::SyncSender<Command> {
-        let (sender, receiver) = mpsc::sync_channel(self.log_queue_len);
-        self.run_thread(receiver);
-        sender
+    fn run(mut self) -> (QueueSender, JoinHandle<Result<()>>) {
+        let (sender, receiver) = queue(self.log_queue_len, self.priority, self.memory_budget);
+        self.budget = receiver.budget();
+        (sender, self.run_thread(receiver))
     }
 
     fn next_deadline(&self) -> Option<Instant> {
-        if self.buffer.is_empty() && self.buffer_size.is_some() {
-            return self.buffer_lifetime.map(|lt| Instant::now() + lt);
+        if self.buffer_size.is_some() {
+            return self
+                .deadline
+                .or_else(|| self.buffer_lifetime.map(|lt| Instant::now() + lt));
         }
         None
     }
 
-    fn run_thread(mut self, receiver: mpsc::Receiver<Command>) {
+    fn run_thread(mut self, receiver: QueueReceiver) -> JoinHandle<Result<()>> {
         std::thread::spawn::<_, Result<()>>(move || {
+            mark_delivering_thread();
+            if let Some(config) = self.locked_spool.take() {
+                let err = Error::SpoolLocked(config.path.display().to_string());
+                error_handler::report(&*self.error_handler, &ErrorEvent::new(err, 0));
+                match Spool::open_wait(config) {
+                    Ok(spool) => self.spool = Some(spool),
+                    Err(err) => {
+                        let event = ErrorEvent::new(err, 0);
+                        error_handler::report(&*self.error_handler, &event);
+                        return Err(event.error);
+                    }
+                }
+            }
+            // Replay records left in the spool by previous run
+            if self
+                .spool
+                .as_ref()
+                .map(|s| s.pending() > 0)
+                .unwrap_or(false)
             {
-                let mut last_error: Option<Instant> = None;
-                loop {
-                    let cmd = match self.deadline {
-                        Some(deadline) => receiver
-                            .recv_timeout(deadline.saturating_duration_since(Instant::now())),
-                        None => receiver
-                            .recv()
-                            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
-                    };
-
-                    if let Ok(Command::SendBatch(_) | Command::Send(_)) = &cmd {
-                        self.deadline = self.next_deadline();
+                self.deadline = Some(Instant::now());
+            }
+            self.report_at = self.reporter.as_ref().map(|r| Instant::now() + r.interval);
+            loop {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| self.run_loop(&receiver)))
+                    .unwrap_or_else(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        let err =
+                            Error::FatalInternal(format!("sender thread panicked: {}", message));
+                        self.metrics.add_error(&err);
+                        let event = ErrorEvent::new(err, 0);
+                        error_handler::report(&*self.error_handler, &event);
+                        Err(event.error)
+                    });
+                match result {
+                    Err(err) if self.stopping.is_none() => {
+                        // The queue stays open while the sender is recreated
+                        if !self.restart() {
+                            return Err(err);
+                        }
                     }
-                    let _ = match cmd {
-                        Ok(Command::Flush) | Err(mpsc::RecvTimeoutError::Timeout) => self.flush(),
-                        Ok(Command::Send(event)) => self.send(event),
-                        Ok(Command::SendBatch(events)) => self.send_batch(events),
-                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    result => return result,
+                }
+            }
+        })
+    }
+
+    /// Processes commands until shutdown or fatal error
+    fn run_loop(&mut self, receiver: &QueueReceiver) -> Result<()> {
+        loop {
+            let summary_at = self.rate_limiter.as_ref().map(|l| l.summary_at());
+            let dedup_at = self.dedup.as_ref().and_then(|d| d.next_expiry());
+            let wakeup = [self.deadline, self.report_at, summary_at, dedup_at]
+                .iter()
+                .flatten()
+                .min()
+                .copied();
+            let cmd = receiver.recv_timeout(
+                wakeup.map(|wakeup| wakeup.saturating_duration_since(Instant::now())),
+            );
+
+            if let Ok((Command::SendBatch(_) | Command::Send(_), _)) = &cmd {
+                self.deadline = self.next_deadline();
+            }
+            let mut stop = false;
+            let dropped_before = self.metrics.dropped_total();
+            match cmd {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                        self.flush()
+                    } else {
+                        Ok(())
                     }
-                    .or_else(|err| {
-                        if last_error
-                            .as_ref()
-                            .map(|x| x.elapsed() > self.error_period)
-                            .unwrap_or(true)
-                        {
-                            println!("logstash logger error: {}", err);
-                            last_error = Some(Instant::now());
-                        }
-                        if matches!(
-                            err,
-                            Error::FatalInternal(..) | Error::SenderThreadStopped(..)
-                        ) {
-                            Result::Err(err)
-                        } else {
-                            Result::Ok(())
-                        }
-                    })?;
                 }
-                Ok(())
+                // All senders are dropped, buffered records are still sent
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    stop = true;
+                    self.shutdown(receiver, None)
+                }
+                Ok((Command::Shutdown(ticket, deadline), _)) => {
+                    stop = true;
+                    self.stopping = Some(ticket);
+                    self.shutdown(receiver, Some(deadline))
+                }
+                Ok((command, size)) => self.process(command, size),
             }
-            .map_err(|err| {
-                println!("fatal logger error: {}", err);
-                err
-            })
+            .and_then(|()| self.send_rate_limit_summary(false))
+            .and_then(|()| self.send_collapsed(false))
+            .or_else(|err| {
+                self.metrics.add_error(&err);
+                let dropped = self.metrics.dropped_total() - dropped_before;
+                let event = ErrorEvent::new(err, dropped);
+                error_handler::report(&*self.error_handler, &event);
+                if event.fatal {
+                    Result::Err(event.error)
+                } else {
+                    Result::Ok(())
+                }
+            })?;
+            self.update_pending();
+            self.report_metrics(receiver);
+            if stop {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Replaces the sender with a fresh one from the factory, returns `false` if restart
+    /// is not configured or restart limit is reached
+    fn restart(&mut self) -> bool {
+        let factory = match &self.factory {
+            Some(factory) => factory,
+            None => return false,
+        };
+        while self
+            .restart
+            .max_restarts
+            .is_none_or(|max| self.restarts < max)
+        {
+            std::thread::sleep(self.restart.backoff);
+            self.restarts += 1;
+            self.metrics.add_restart();
+            match factory() {
+                Ok(sender) => {
+                    self.sender = sender;
+                    return true;
+                }
+                Err(err) => {
+                    self.metrics.add_error(&err);
+                    error_handler::report(&*self.error_handler, &ErrorEvent::new(err, 0));
+                }
+            }
+        }
+        false
+    }
+
+    fn process(&mut self, command: Command, size: usize) -> Result<()> {
+        match command {
+            Command::Flush => self.flush(),
+            Command::Send(event) => {
+                // Size is measured by the queue only if memory budget is set
+                let size = match self.budget {
+                    Some(_) => size,
+                    None => self.record_size(&event),
+                };
+                match self.dedupe(event, size) {
+                    Some(event) => self.send(event, size),
+                    None => Ok(()),
+                }
+            }
+            Command::SendBatch(events) => self.send_batch(events),
+            Command::FlushBlocking(ticket) => {
+                let collapsed = self.send_collapsed(true);
+                let result = collapsed.and(self.flush());
+                self.waiting.push(PendingFlush {
+                    ticket,
+                    retry_mark: self.retry.as_ref().map_or(0, |r| r.mark()),
+                    spool_mark: self.spool.as_ref().map(|s| s.end()),
+                    // Records dropped before the flush was processed are reported too
+                    lost_mark: std::mem::replace(&mut self.lost_mark, self.lost),
+                    failed: result.is_err() && self.retry.is_none() && self.spool.is_none(),
+                });
+                result
+            }
+            // Only one shutdown is possible
+            Command::Shutdown(..) => Ok(()),
+        }
+    }
+
+    /// Sends records left in the queue and the buffer, retries failed batches until the deadline
+    fn shutdown(&mut self, receiver: &QueueReceiver, deadline: Option<Instant>) -> Result<()> {
+        let expired = |deadline: Option<Instant>| deadline.is_some_and(|d| Instant::now() >= d);
+        let mut result = Ok(());
+        while !expired(deadline) {
+            match receiver.recv_timeout(Some(Duration::ZERO)) {
+                Ok((command, size)) => result = result.and(self.process(command, size)),
+                Err(_) => break,
+            }
+        }
+        result = result.and(self.send_rate_limit_summary(true));
+        result = result.and(self.send_collapsed(true));
+        result = result.and(self.flush());
+        while let Some(next_attempt) = self.retry.as_ref().and_then(|r| r.next_attempt()) {
+            let deadline = match deadline {
+                Some(deadline) if next_attempt < deadline => deadline,
+                _ => break,
+            };
+            std::thread::sleep(next_attempt.saturating_duration_since(Instant::now()));
+            if expired(Some(deadline)) {
+                break;
+            }
+            result = self.retry_pending();
+        }
+        if let Some(spool) = &mut self.spool {
+            spool.sync()?;
+        }
+        result
+    }
+
+    fn report_metrics(&mut self, receiver: &QueueReceiver) {
+        let (reporter, report_at) = match (&self.reporter, self.report_at) {
+            (Some(reporter), Some(report_at)) if Instant::now() >= report_at => {
+                (reporter, report_at)
+            }
+            _ => return,
+        };
+        let mut snapshot = self.metrics.snapshot();
+        snapshot.queue_depth = receiver.records();
+        (reporter.callback)(&snapshot);
+        self.report_at = Some(report_at + reporter.interval);
+    }
+
+    /// Sends the record with counts of suppressed records when it is due
+    fn send_rate_limit_summary(&mut self, force: bool) -> Result<()> {
+        let summary = self
+            .rate_limiter
+            .as_ref()
+            .and_then(|limiter| limiter.take_summary(force));
+        match summary {
+            // It didn't pass the log queue, so it isn't accounted in the memory budget
+            Some(event) => self.send_unbuffered(event),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the record if it is not a duplicate of a recent one
+    fn dedupe(&mut self, event: LogStashRecord, size: usize) -> Option<LogStashRecord> {
+        let dedup = match &mut self.dedup {
+            Some(dedup) => dedup,
+            None => return Some(event),
+        };
+        match dedup.check(event, size) {
+            Dedupe::Send(event) => Some(event),
+            Dedupe::Held => None,
+            Dedupe::Collapsed => {
+                self.release(size);
+                self.metrics.add_dropped(DropReason::Collapsed, 1);
+                None
+            }
+        }
+    }
+
+    /// Sends held duplicates whose window has ended, or all of them if `force` is set
+    fn send_collapsed(&mut self, force: bool) -> Result<()> {
+        let collapsed = match &mut self.dedup {
+            Some(dedup) => dedup.take_expired(force),
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        for (event, size) in collapsed {
+            result = result.and(self.send(event, size));
+        }
+        result
+    }
+
+    /// Publishes number of held records and completes blocking flushes
+    fn update_pending(&mut self) {
+        let held = self.buffer.len()
+            + self.dedup.as_ref().map_or(0, |d| d.held())
+            + self.retry.as_ref().map_or(0, |r| r.records())
+            + self.spool.as_ref().map_or(0, |s| s.pending());
+        self.held.store(held, Ordering::Relaxed);
+        let retry = self.retry.as_ref();
+        let spool = self.spool.as_ref();
+        let lost = self.lost;
+        self.waiting.retain(|flush| {
+            let delivered = retry.is_none_or(|r| r.is_delivered(flush.retry_mark))
+                && spool
+                    .zip(flush.spool_mark)
+                    .is_none_or(|(s, mark)| s.is_acked(mark));
+            if delivered {
+                let dropped = lost - flush.lost_mark;
+                flush.ticket.complete(match dropped {
+                    0 if !flush.failed => FlushOutcome::Delivered,
+                    dropped => FlushOutcome::Failed(dropped),
+                });
+            }
+            !delivered
         });
     }
 
-    fn send(&mut self, event: LogStashRecord) -> Result<()> {
-        if event.level >= self.ignore_buffer {
-            self.sender.send(event)?;
-        } else if let Some(max_size) = self.buffer_size {
-            self.buffer.push(event);
-            if self.buffer.len() >= max_size {
-                self.flush()?;
+    /// Serialized size of the record if it is needed for byte limits
+    fn record_size(&self, event: &LogStashRecord) -> usize {
+        if self.max_batch_bytes.is_some() || self.budget.is_some() {
+            event.serialized_len()
+        } else {
+            0
+        }
+    }
+
+    /// Counts records dropped after they were accepted
+    fn add_dropped(&mut self, reason: DropReason, records: usize) {
+        self.metrics.add_dropped(reason, records);
+        self.lost += records;
+    }
+
+    /// Returns bytes of records which left the sender thread to the memory budget
+    fn release(&self, bytes: usize) {
+        if let Some(budget) = &self.budget {
+            budget.release(bytes);
+        }
+    }
+
+    fn send(&mut self, event: LogStashRecord, size: usize) -> Result<()> {
+        if let Some(max_batch_bytes) = self.max_batch_bytes {
+            if size > max_batch_bytes {
+                return self.send_oversized(event, size, max_batch_bytes);
+            }
+        }
+        let max_size = match self.buffer_size {
+            Some(max_size) if event.level < self.ignore_buffer && self.spool.is_none() => max_size,
+            _ => {
+                let result = self.send_unbuffered(event);
+                self.release(size);
+                return result;
+            }
+        };
+        let max_bytes = self.max_batch_bytes.unwrap_or(usize::MAX);
+        let mut result = Ok(());
+        // Buffered records are sent first if the new one doesn't fit into the batch
+        if !self.buffer.is_empty() && self.buffer_bytes + size > max_bytes {
+            result = self.flush();
+        }
+        self.buffer.push(event);
+        self.buffer_bytes += size;
+        if self.buffer.len() >= max_size || self.buffer_bytes >= max_bytes {
+            self.flush()?;
+        }
+        result
+    }
+
+    fn send_unbuffered(&mut self, event: LogStashRecord) -> Result<()> {
+        if let Some(spool) = &mut self.spool {
+            let dropped = spool.append(&event)?;
+            let immediate = event.level >= self.ignore_buffer || self.buffer_size.is_none();
+            let flush = immediate || spool.pending() >= self.buffer_size.unwrap_or_default();
+            // Drops are reported even if the flush fails
+            self.add_dropped(DropReason::SpoolOverflow, dropped as usize);
+            let result = if flush { self.flush() } else { Ok(()) };
+            if dropped > 0 {
+                return Err(Error::SpoolOverflow(dropped));
             }
+            result
+        } else if self.retry.is_some() {
+            self.send_with_retry(vec![event])
         } else {
-            self.sender.send(event)?;
+            let result = self.sender.send(event);
+            if result.is_err() {
+                self.add_dropped(DropReason::SendFailed, 1);
+            }
+            result
+        }
+    }
+
+    fn send_oversized(
+        &mut self,
+        mut event: LogStashRecord,
+        size: usize,
+        max_batch_bytes: usize,
+    ) -> Result<()> {
+        match self.oversized_policy {
+            OversizedPolicy::SendAlone => {
+                // Buffered records are sent first to keep the order
+                let flushed = if self.spool.is_none() {
+                    self.flush()
+                } else {
+                    Ok(())
+                };
+                let result = self.send_unbuffered(event);
+                self.release(size);
+                flushed.and(result)
+            }
+            OversizedPolicy::Truncate => match truncate_message(&mut event, max_batch_bytes) {
+                Some(new_size) => {
+                    self.release(size - new_size);
+                    self.send(event, new_size)
+                }
+                None => {
+                    self.release(size);
+                    self.add_dropped(DropReason::Oversized, 1);
+                    Err(Error::RecordTooLarge(size))
+                }
+            },
+            OversizedPolicy::Drop => {
+                self.release(size);
+                self.add_dropped(DropReason::Oversized, 1);
+                Err(Error::RecordTooLarge(size))
+            }
+        }
+    }
+
+    /// Sends batch keeping it for retry on failure, older failed batches are sent first
+    fn send_with_retry(&mut self, events: Vec<LogStashRecord>) -> Result<()> {
+        let retry = self.retry.as_mut().expect("retry is enabled");
+        if retry.is_empty() {
+            if let Err(err) = self.sender.send_batch(events.clone()) {
+                retry.push(events);
+                self.deadline = Some(retry.schedule());
+                return Err(err);
+            }
+            return Ok(());
+        }
+        let dropped = retry.push(events);
+        self.add_dropped(DropReason::RetryOverflow, dropped);
+        self.retry_pending()?;
+        if dropped > 0 {
+            return Err(Error::RetryOverflow(dropped));
         }
         Ok(())
     }
 
-    fn send_batch(&mut self, events: Vec<LogStashRecord>) -> Result<()> {
-        for event in events {
-            self.send(event)?;
+    fn retry_pending(&mut self) -> Result<()> {
+        let retry = self.retry.as_mut().expect("retry is enabled");
+        if !retry.is_ready() {
+            self.deadline = retry.next_attempt();
+            return Ok(());
+        }
+        while let Some(batch) = retry.front() {
+            if let Err(err) = self.sender.send_batch(batch.clone()) {
+                self.deadline = Some(retry.schedule());
+                return Err(err);
+            }
+            retry.pop_front();
         }
         Ok(())
     }
 
+    fn send_batch(&mut self, events: Vec<LogStashRecord>) -> Result<()> {
+        let mut result = Ok(());
+        let mut events = events.into_iter();
+        while let Some(event) = events.next() {
+            let size = self.record_size(&event);
+            let event = match self.dedupe(event, size) {
+                Some(event) => event,
+                None => continue,
+            };
+            if let Err(err) = self.send(event, size) {
+                // Spool and retry queue keep the rest of the batch, the first error is returned
+                if self.spool.is_some() || self.retry.is_some() {
+                    result = result.and(Err(err));
+                    continue;
+                }
+                let rest: Vec<_> = events.collect();
+                self.release(rest.iter().map(|e| self.record_size(e)).sum());
+                self.add_dropped(DropReason::SendFailed, rest.len());
+                return Err(err);
+            }
+        }
+        result
+    }
+
     fn flush(&mut self) -> Result<()> {
+        if self.spool.is_some() {
+            return self.flush_spool();
+        }
+        // Failed flush is not repeated until new records or retry
+        self.deadline = None;
         if !self.buffer.is_empty() {
             let buffer = std::mem::replace(
                 &mut self.buffer,
                 Vec::with_capacity(self.buffer_size.unwrap_or_default()),
             );
-            self.sender.send_batch(buffer)?;
+            let bytes = std::mem::take(&mut self.buffer_bytes);
+            let records = buffer.len();
+            let result = if self.retry.is_some() {
+                self.send_with_retry(buffer)
+            } else {
+                let result = self.sender.send_batch(buffer);
+                if result.is_err() {
+                    self.add_dropped(DropReason::SendFailed, records);
+                }
+                result
+            };
+            self.release(bytes);
+            result?;
+        } else if self.retry.is_some() {
+            self.retry_pending()?;
+        }
+        self.sender.flush()?;
+        self.deadline = self.retry.as_ref().and_then(|r| r.next_attempt());
+        Ok(())
+    }
+
+    fn flush_spool(&mut self) -> Result<()> {
+        let spool = self.spool.as_mut().expect("spool is enabled");
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                spool.sync()?;
+                self.deadline = Some(retry_at);
+                return Ok(());
+            }
+        }
+        let batch_size = self.buffer_size.unwrap_or(SPOOL_READ_BATCH);
+        while spool.pending() > 0 {
+            let mut batch = spool.read(batch_size, self.max_batch_bytes)?;
+            if batch.is_exhausted() {
+                spool.ack(&batch)?;
+                break;
+            }
+            let events = std::mem::take(&mut batch.events);
+            if let Err(err) = self.sender.send_batch(events) {
+                // Records stay in the spool until the next attempt
+                let retry_at = Instant::now() + spool.retry_interval();
+                self.retry_at = Some(retry_at);
+                self.deadline = Some(retry_at);
+                return Err(err);
+            }
+            spool.ack(&batch)?;
         }
+        self.retry_at = None;
         self.sender.flush()?;
         self.deadline = None;
         Ok(())
@@ -213,3 +1344,249 @@ impl log::Log for BufferedSender {
         let _ = Sender::flush(self);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+
+    /// Collects sent records, fails while `failing` is set
+    #[derive(Clone, Default)]
+    struct TestSender {
+        sent: Arc<Mutex<Vec<LogStashRecord>>>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl TestSender {
+        fn failing() -> Self {
+            let sender = Self::default();
+            sender.set_failing(true);
+            sender
+        }
+
+        fn set_failing(&self, failing: bool) {
+            self.failing.store(failing, Ordering::SeqCst);
+        }
+
+        fn messages(&self) -> Vec<String> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter()
+                .map(|e| e.fields["message"].to_string())
+                .collect()
+        }
+    }
+
+    impl Sender for TestSender {
+        fn send(&self, event: LogStashRecord) -> Result<()> {
+            self.send_batch(vec![event])
+        }
+
+        fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(Error::IO(std::io::ErrorKind::ConnectionRefused.into()));
+            }
+            self.sent.lock().unwrap().extend(events);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(message: &str) -> LogStashRecord {
+        let mut event = LogStashRecord::new();
+        event.add_data("message", message.into());
+        event
+    }
+
+    fn records(count: usize) -> Vec<LogStashRecord> {
+        (0..count).map(|i| record(&format!("m{}", i))).collect()
+    }
+
+    fn quoted(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("\"m{}\"", i)).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "logstash-rs-buffer-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn spool_keeps_failed_batch() {
+        let dir = temp_dir("spool-batch");
+        let sender = TestSender::failing();
+        let buffered = BufferedSender::builder(sender.clone())
+            .with_buffer_size(Some(2))
+            .with_error_handler(|_: &ErrorEvent| {})
+            .with_spool(SpoolConfig::new(&dir).with_retry_interval(Duration::from_millis(10)))
+            .build()
+            .unwrap();
+
+        buffered.send_batch(records(6)).unwrap();
+        assert_eq!(
+            buffered.flush_blocking(Duration::from_millis(50)).unwrap(),
+            6
+        );
+        sender.set_failing(false);
+        assert_eq!(buffered.flush_blocking(TIMEOUT).unwrap(), 0);
+
+        assert_eq!(sender.messages(), quoted(6));
+        assert_eq!(buffered.metrics().dropped_total(), 0);
+        buffered.shutdown(TIMEOUT).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spool_overflow_is_reported_when_flush_fails() {
+        let dir = temp_dir("spool-overflow");
+        let errors = Arc::new(Mutex::new(vec![]));
+        let handler_errors = errors.clone();
+        let buffered = BufferedSender::builder(TestSender::failing())
+            .with_buffer_size(None)
+            .with_error_handler(move |event: &ErrorEvent| {
+                handler_errors.lock().unwrap().push(event.kind);
+            })
+            // Every record is sent again and fails
+            .with_spool(
+                SpoolConfig::new(&dir)
+                    .with_max_size(200)
+                    .with_retry_interval(Duration::ZERO),
+            )
+            .build()
+            .unwrap();
+
+        for event in records(20) {
+            buffered.send(event).unwrap();
+        }
+        buffered.flush_blocking(Duration::from_millis(50)).unwrap();
+
+        let metrics = buffered.metrics();
+        assert!(metrics
+            .dropped
+            .get(&DropReason::SpoolOverflow)
+            .is_some_and(|d| *d > 0));
+        assert!(errors.lock().unwrap().contains(&"spool_overflow"));
+        drop(buffered);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn retry(max_batches: usize) -> RetryConfig {
+        let backoff = Duration::from_millis(10);
+        RetryConfig::default()
+            .with_max_batches(max_batches)
+            .with_backoff(backoff, backoff)
+    }
+
+    #[test]
+    fn retry_keeps_failed_batch_in_order() {
+        let sender = TestSender::failing();
+        let buffered = BufferedSender::builder(sender.clone())
+            .with_buffer_size(Some(2))
+            .with_error_handler(|_: &ErrorEvent| {})
+            .with_retry(retry(100))
+            .build()
+            .unwrap();
+
+        buffered.send_batch(records(6)).unwrap();
+        assert_eq!(
+            buffered.flush_blocking(Duration::from_millis(50)).unwrap(),
+            6
+        );
+        sender.set_failing(false);
+        assert_eq!(buffered.flush_blocking(TIMEOUT).unwrap(), 0);
+
+        assert_eq!(sender.messages(), quoted(6));
+        assert_eq!(buffered.metrics().dropped_total(), 0);
+    }
+
+    #[test]
+    fn retry_overflow_drops_oldest_batches() {
+        let sender = TestSender::failing();
+        let buffered = BufferedSender::builder(sender.clone())
+            .with_buffer_size(None)
+            .with_error_handler(|_: &ErrorEvent| {})
+            .with_retry(retry(2))
+            .build()
+            .unwrap();
+
+        buffered.send_batch(records(6)).unwrap();
+        assert_eq!(
+            buffered.flush_blocking(Duration::from_millis(50)).unwrap(),
+            2
+        );
+        sender.set_failing(false);
+        assert_eq!(buffered.flush_blocking(TIMEOUT).unwrap(), 0);
+
+        assert_eq!(sender.messages(), ["\"m4\"", "\"m5\""]);
+        assert_eq!(buffered.metrics().dropped[&DropReason::RetryOverflow], 4);
+    }
+
+    #[test]
+    fn oversized_record_is_truncated_and_sent() {
+        let sender = TestSender::default();
+        let buffered = BufferedSender::builder(sender.clone())
+            .with_buffer_size(Some(10))
+            .with_max_batch_bytes(200)
+            .with_oversized_policy(OversizedPolicy::Truncate)
+            .build()
+            .unwrap();
+        // Multibyte and escaped characters
+        let message = "é\"ü\\".repeat(50);
+        let event = record(&message);
+        assert!(event.serialized_len() > 400);
+
+        buffered.send(event).unwrap();
+        assert_eq!(buffered.flush_blocking(TIMEOUT).unwrap(), 0);
+
+        let sent = sender.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        // Cut by whole characters, at most 2 bytes escaped or UTF-8 here
+        let size = sent[0].serialized_len();
+        assert!(size <= 200 && size > 197, "{}", size);
+        assert_eq!(sent[0].fields["tags"], serde_json::json!([TRUNCATED_TAG]));
+        let truncated = sent[0].fields["message"].as_str().unwrap();
+        let prefix = truncated.strip_suffix(TRUNCATION_MARKER).unwrap();
+        assert!(!prefix.is_empty() && message.starts_with(prefix));
+        assert_eq!(buffered.metrics().dropped_total(), 0);
+    }
+
+    #[test]
+    fn locked_spool_is_opened_after_release() {
+        let dir = temp_dir("spool-lock");
+        let spool = || SpoolConfig::new(&dir).with_retry_interval(Duration::from_millis(10));
+        let (first_sender, second_sender) = (TestSender::default(), TestSender::default());
+        let first = BufferedSender::builder(first_sender.clone())
+            .with_spool(spool())
+            .build()
+            .unwrap();
+        first.send(record("m0")).unwrap();
+        assert_eq!(first.flush_blocking(TIMEOUT).unwrap(), 0);
+
+        let second = BufferedSender::builder(second_sender.clone())
+            .with_error_handler(|_: &ErrorEvent| {})
+            .with_spool(spool())
+            .build()
+            .unwrap();
+        second.send(record("m1")).unwrap();
+        assert!(second.flush_blocking(Duration::from_millis(50)).unwrap() > 0);
+        assert!(second_sender.messages().is_empty());
+
+        first.shutdown(TIMEOUT).unwrap();
+        assert_eq!(second.flush_blocking(TIMEOUT).unwrap(), 0);
+        assert_eq!(first_sender.messages(), quoted(1));
+        assert_eq!(second_sender.messages(), ["\"m1\""]);
+        second.shutdown(TIMEOUT).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use crate::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::Level;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Settings of duplicate record collapsing in the sender thread.
+///
+/// The first record with a level, target and message template is sent as usual. Records with
+/// the same template received within `window` after it are held back and sent at the end of the
+/// window as one record with `repeat_count`, `first_timestamp` and `last_timestamp` fields. The
+/// sent record is the first held one, `first_timestamp` is the time of the record that opened
+/// the window.
+///
+/// `log` does not expose format strings, so the template is identified by the call site (file
+/// and line). Records without a call site are compared by their message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedupConfig {
+    pub window: Duration,
+    /// Maximum number of distinct records tracked at once, others are sent without collapsing
+    pub max_keys: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            max_keys: 1000,
+        }
+    }
+}
+
+impl DedupConfig {
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Template {
+    CallSite(String, u32),
+    Message(Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DedupKey {
+    level: Level,
+    target: String,
+    template: Template,
+}
+
+impl DedupKey {
+    fn new(event: &LogStashRecord) -> Self {
+        let template = match (&event.file, event.line) {
+            (Some(file), Some(line)) => Template::CallSite(file.clone(), line),
+            _ => Template::Message(match event.fields.get("message") {
+                Some(Value::String(message)) => Some(message.clone()),
+                Some(message) => Some(message.to_string()),
+                None => None,
+            }),
+        };
+        Self {
+            level: event.level,
+            target: event.target.clone(),
+            template,
+        }
+    }
+}
+
+/// Window opened by a sent record
+#[derive(Debug)]
+struct Entry {
+    first: DateTime<Utc>,
+    repeats: Option<Repeats>,
+}
+
+/// Duplicates received during the window
+#[derive(Debug)]
+struct Repeats {
+    /// The first duplicate with its size, sent with the count
+    event: LogStashRecord,
+    size: usize,
+    count: u64,
+    last: DateTime<Utc>,
+}
+
+/// What to do with the checked record
+pub(crate) enum Dedupe {
+    /// Not a duplicate, should be sent
+    Send(LogStashRecord),
+    /// Kept until the end of the window, the size stays accounted
+    Held,
+    /// Counted in the held duplicate, the size should be released
+    Collapsed,
+}
+
+#[derive(Debug)]
+pub(crate) struct Dedup {
+    config: DedupConfig,
+    entries: HashMap<DedupKey, Entry>,
+    /// Window ends in order of creation
+    expiry: VecDeque<(Instant, DedupKey)>,
+    held: usize,
+}
+
+impl Dedup {
+    pub(crate) fn new(config: DedupConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            expiry: VecDeque::new(),
+            held: 0,
+        }
+    }
+
+    pub(crate) fn check(&mut self, event: LogStashRecord, size: usize) -> Dedupe {
+        let key = DedupKey::new(&event);
+        match self.entries.get_mut(&key).map(|entry| &mut entry.repeats) {
+            Some(Some(repeats)) => {
+                repeats.count += 1;
+                repeats.last = event.timestamp;
+                Dedupe::Collapsed
+            }
+            Some(repeats) => {
+                *repeats = Some(Repeats {
+                    last: event.timestamp,
+                    event,
+                    size,
+                    count: 1,
+                });
+                self.held += 1;
+                Dedupe::Held
+            }
+            None => {
+                if self.entries.len() < self.config.max_keys {
+                    self.expiry
+                        .push_back((Instant::now() + self.config.window, key.clone()));
+                    let entry = Entry {
+                        first: event.timestamp,
+                        repeats: None,
+                    };
+                    self.entries.insert(key, entry);
+                }
+                Dedupe::Send(event)
+            }
+        }
+    }
+
+    /// End of the earliest window
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.expiry.front().map(|(expires, _)| *expires)
+    }
+
+    /// Number of held duplicates
+    pub(crate) fn held(&self) -> usize {
+        self.held
+    }
+
+    /// Ends expired windows, or all of them if `force` is set, and returns collapsed records
+    /// with their sizes
+    pub(crate) fn take_expired(&mut self, force: bool) -> Vec<(LogStashRecord, usize)> {
+        let now = Instant::now();
+        let mut collapsed = vec![];
+        while let Some((expires, _)) = self.expiry.front() {
+            if !force && *expires > now {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().expect("checked above");
+            if let Some(Entry {
+                first,
+                repeats: Some(repeats),
+            }) = self.entries.remove(&key)
+            {
+                self.held -= 1;
+                let mut event = repeats.event;
+                event.add_data("repeat_count", repeats.count.into());
+                event.add_data("first_timestamp", format_timestamp(first).into());
+                event.add_data("last_timestamp", format_timestamp(repeats.last).into());
+                collapsed.push((event, repeats.size));
+            }
+        }
+        collapsed
+    }
+}
+
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn record(message: &str, line: Option<u32>, second: u32) -> LogStashRecord {
+        let mut event = LogStashRecord::new();
+        event.timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, second).unwrap();
+        event.file = line.map(|_| "src/main.rs".to_string());
+        event.line = line;
+        event.add_data("message", message.into());
+        event
+    }
+
+    fn dedup() -> Dedup {
+        Dedup::new(DedupConfig::default())
+    }
+
+    #[test]
+    fn records_of_one_call_site_are_collapsed() {
+        let mut dedup = dedup();
+        assert!(matches!(
+            dedup.check(record("id 1", Some(10), 1), 10),
+            Dedupe::Send(_)
+        ));
+        assert!(matches!(
+            dedup.check(record("id 2", Some(10), 2), 10),
+            Dedupe::Held
+        ));
+        assert!(matches!(
+            dedup.check(record("id 3", Some(10), 3), 10),
+            Dedupe::Collapsed
+        ));
+        assert!(matches!(
+            dedup.check(record("id 1", Some(11), 4), 10),
+            Dedupe::Send(_)
+        ));
+
+        let collapsed = dedup.take_expired(true);
+        assert_eq!(collapsed.len(), 1);
+        let fields = &collapsed[0].0.fields;
+        assert_eq!(fields["message"], "id 2");
+        assert_eq!(fields["repeat_count"], 2);
+        assert_eq!(fields["first_timestamp"], "2024-01-01T00:00:01.000Z");
+        assert_eq!(fields["last_timestamp"], "2024-01-01T00:00:03.000Z");
+        assert_eq!(dedup.held(), 0);
+    }
+
+    #[test]
+    fn records_without_call_site_are_compared_by_message() {
+        let mut dedup = dedup();
+        assert!(matches!(
+            dedup.check(record("a", None, 1), 10),
+            Dedupe::Send(_)
+        ));
+        assert!(matches!(
+            dedup.check(record("b", None, 2), 10),
+            Dedupe::Send(_)
+        ));
+        assert!(matches!(
+            dedup.check(record("a", None, 3), 10),
+            Dedupe::Held
+        ));
+    }
+
+    #[test]
+    fn records_over_max_keys_are_sent() {
+        let mut dedup = Dedup::new(DedupConfig::default().with_max_keys(1));
+        assert!(matches!(
+            dedup.check(record("a", Some(1), 1), 10),
+            Dedupe::Send(_)
+        ));
+        assert!(matches!(
+            dedup.check(record("b", Some(2), 2), 10),
+            Dedupe::Send(_)
+        ));
+        assert!(matches!(
+            dedup.check(record("b", Some(2), 3), 10),
+            Dedupe::Send(_)
+        ));
+        assert!(dedup.take_expired(true).is_empty());
+    }
+}
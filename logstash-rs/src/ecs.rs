@@ -0,0 +1,100 @@
+use crate::prelude::*;
+use chrono::SecondsFormat;
+use serde_json::{Map, Value};
+
+/// Version of Elastic Common Schema written to `ecs.version`
+pub const ECS_VERSION: &str = "8.11.0";
+
+/// Layout of records sent to Logstash.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputSchema {
+    /// Record fields as they are
+    #[default]
+    Legacy,
+    /// Elastic Common Schema
+    Ecs(EcsConfig),
+}
+
+/// Service and host description added to records in ECS mode.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct EcsConfig {
+    pub service_name: Option<String>,
+    pub service_version: Option<String>,
+    pub service_environment: Option<String>,
+    /// Taken from `HOSTNAME` or `COMPUTERNAME` environment variable by default
+    pub host_name: Option<String>,
+}
+
+impl Default for EcsConfig {
+    fn default() -> Self {
+        Self {
+            service_name: None,
+            service_version: None,
+            service_environment: None,
+            host_name: std::env::var("HOSTNAME")
+                .or_else(|_| std::env::var("COMPUTERNAME"))
+                .ok(),
+        }
+    }
+}
+
+impl EcsConfig {
+    pub fn with_service_name(mut self, name: impl Into<String>) -> Self {
+        self.service_name = Some(name.into());
+        self
+    }
+
+    pub fn with_service_version(mut self, version: impl Into<String>) -> Self {
+        self.service_version = Some(version.into());
+        self
+    }
+
+    pub fn with_service_environment(mut self, environment: impl Into<String>) -> Self {
+        self.service_environment = Some(environment.into());
+        self
+    }
+
+    pub fn with_host_name(mut self, name: impl Into<String>) -> Self {
+        self.host_name = Some(name.into());
+        self
+    }
+}
+
+impl LogStashRecord {
+    /// Converts the record to ECS document, other fields are kept and merged with ECS objects
+    pub fn to_ecs(&self, config: &EcsConfig) -> Value {
+        let mut ecs = LogStashRecord {
+            fields: self.fields.clone(),
+            ..Default::default()
+        };
+        let strings = [
+            ("log.level", Some(self.level.as_str().to_lowercase())),
+            ("log.logger", Some(self.target.clone())),
+            ("log.origin.file.name", self.file.clone()),
+            ("log.origin.function", self.module.clone()),
+            ("ecs.version", Some(ECS_VERSION.to_string())),
+            ("service.name", config.service_name.clone()),
+            ("service.version", config.service_version.clone()),
+            ("service.environment", config.service_environment.clone()),
+            ("host.name", config.host_name.clone()),
+        ];
+        for (path, value) in strings.iter() {
+            if let Some(value) = value {
+                ecs.set_path(path, value.as_str().into());
+            }
+        }
+        if let Some(line) = self.line {
+            ecs.set_path("log.origin.file.line", line.into());
+        }
+        let mut document: Map<String, Value> = ecs.fields.into_iter().collect();
+        document.insert(
+            "@timestamp".to_string(),
+            self.timestamp
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        Value::Object(document)
+    }
+}
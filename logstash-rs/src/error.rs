@@ -8,25 +8,79 @@ pub enum Error {
     FmtError(#[from] std::fmt::Error),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
-    #[cfg(all(feature = "tls", not(feature = "rustls")))]
+    #[cfg(feature = "tls")]
     #[error(transparent)]
     TlsError(#[from] native_tls::Error),
     #[error("sender thread stopped: {0}")]
     SenderThreadStopped(String),
     #[error("address resolution error: {0}:{1}")]
     AddressResolution(String, u16),
+    #[error("proxy error: {0}")]
+    Proxy(String),
     #[error("fatal internal error: {0}")]
     FatalInternal(String),
-    #[cfg(all(not(feature = "tls"), feature = "rustls"))]
+    #[cfg(feature = "rustls")]
     #[error("rustls client: {0}")]
     InvalidDNSName(#[from] rustls_crate::client::InvalidDnsNameError),
-    #[cfg(all(not(feature = "tls"), feature = "rustls"))]
+    #[cfg(feature = "rustls")]
     #[error("rustls: {0}")]
     Rustls(#[from] rustls_crate::Error),
+    #[error("TLS backend is not available, please enable '{0}' feature")]
+    TlsBackendUnavailable(&'static str),
+    #[error("compression is not available, please enable '{0}' feature")]
+    CompressionUnavailable(&'static str),
+    #[error("codec is not available, please enable '{0}' feature")]
+    CodecUnavailable(&'static str),
+    #[error("encode error: {0}")]
+    Encode(String),
+    #[error("spool directory {0} is used by another instance")]
+    SpoolLocked(String),
+    #[error("spool size limit reached, {0} records dropped")]
+    SpoolOverflow(u64),
+    #[error("retry queue limit reached, {0} records dropped")]
+    RetryOverflow(usize),
+    #[error("record of {0} bytes exceeds batch size limit")]
+    RecordTooLarge(usize),
+    #[error("shutdown timed out with {0} records not sent")]
+    ShutdownTimeout(usize),
+    #[error("flush failed, {0} records dropped")]
+    FlushFailed(usize),
     #[error("buffer is full")]
     BufferFull(),
 }
 
+impl Error {
+    /// Short name of the error variant, used as a key in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::IO(_) => "io",
+            Error::FmtError(_) => "fmt",
+            Error::Serde(_) => "serde",
+            #[cfg(feature = "tls")]
+            Error::TlsError(_) => "tls",
+            Error::SenderThreadStopped(_) => "sender_thread_stopped",
+            Error::AddressResolution(..) => "address_resolution",
+            Error::Proxy(_) => "proxy",
+            Error::FatalInternal(_) => "fatal_internal",
+            #[cfg(feature = "rustls")]
+            Error::InvalidDNSName(_) => "invalid_dns_name",
+            #[cfg(feature = "rustls")]
+            Error::Rustls(_) => "rustls",
+            Error::TlsBackendUnavailable(_) => "tls_backend_unavailable",
+            Error::CompressionUnavailable(_) => "compression_unavailable",
+            Error::CodecUnavailable(_) => "codec_unavailable",
+            Error::Encode(_) => "encode",
+            Error::SpoolLocked(_) => "spool_locked",
+            Error::SpoolOverflow(_) => "spool_overflow",
+            Error::RetryOverflow(_) => "retry_overflow",
+            Error::RecordTooLarge(_) => "record_too_large",
+            Error::ShutdownTimeout(_) => "shutdown_timeout",
+            Error::FlushFailed(_) => "flush_failed",
+            Error::BufferFull() => "buffer_full",
+        }
+    }
+}
+
 impl<T> From<PoisonError<T>> for Error {
     fn from(err: PoisonError<T>) -> Self {
         Self::FatalInternal(err.to_string())
@@ -0,0 +1,115 @@
+use crate::prelude::*;
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Internal error of the logger reported to [`ErrorHandler`].
+#[derive(Debug)]
+pub struct ErrorEvent {
+    pub error: Error,
+    /// Short name of the error variant, see [`Error::kind`]
+    pub kind: &'static str,
+    /// Number of records lost due to the error
+    pub dropped: u64,
+    pub time: SystemTime,
+    /// The sender thread is stopped after fatal error
+    pub fatal: bool,
+}
+
+impl ErrorEvent {
+    pub fn new(error: Error, dropped: u64) -> Self {
+        Self {
+            kind: error.kind(),
+            fatal: matches!(
+                error,
+                Error::FatalInternal(..) | Error::SenderThreadStopped(..)
+            ),
+            error,
+            dropped,
+            time: SystemTime::now(),
+        }
+    }
+}
+
+/// Receiver of internal logger errors.
+///
+/// Records logged from the handler are not sent by the senders of this crate to avoid
+/// feedback loops, so the handler may safely use `log` macros for other appenders.
+pub trait ErrorHandler: Send + Sync + 'static {
+    fn handle(&self, event: &ErrorEvent);
+}
+
+impl<F> ErrorHandler for F
+where
+    F: Fn(&ErrorEvent) + Send + Sync + 'static,
+{
+    fn handle(&self, event: &ErrorEvent) {
+        self(event)
+    }
+}
+
+/// Default handler, prints errors to stderr at most once per period.
+/// Fatal errors are always printed.
+#[derive(Debug)]
+pub struct PrintErrorHandler {
+    period: Duration,
+    /// Time of the last printed error and number of errors suppressed since
+    state: Mutex<(Option<Instant>, usize)>,
+}
+
+impl PrintErrorHandler {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            state: Mutex::new((None, 0)),
+        }
+    }
+}
+
+impl ErrorHandler for PrintErrorHandler {
+    fn handle(&self, event: &ErrorEvent) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, suppressed) = &mut *state;
+        if !event.fatal && last.is_some_and(|last| last.elapsed() <= self.period) {
+            *suppressed += 1;
+            return;
+        }
+        if event.fatal {
+            eprintln!("fatal logger error: {}", event.error);
+        } else if *suppressed > 0 {
+            eprintln!(
+                "logstash logger error: {} ({} similar errors suppressed)",
+                event.error, suppressed
+            );
+        } else {
+            eprintln!("logstash logger error: {}", event.error);
+        }
+        *last = Some(Instant::now());
+        *suppressed = 0;
+    }
+}
+
+thread_local! {
+    static REPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Passes the event to the handler, errors raised while the handler runs are ignored
+pub fn report(handler: &dyn ErrorHandler, event: &ErrorEvent) {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            REPORTING.with(|r| r.set(false));
+        }
+    }
+
+    if REPORTING.with(|r| r.replace(true)) {
+        return;
+    }
+    let _guard = Guard;
+    handler.handle(event);
+}
+
+/// The current thread runs error handler, records should be discarded
+pub fn is_reporting() -> bool {
+    REPORTING.with(|r| r.get())
+}
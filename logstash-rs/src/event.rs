@@ -1,10 +1,10 @@
 use chrono::{DateTime, Utc};
 use log::Level;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{collections::HashMap, time::SystemTime};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogStashRecord {
     #[serde(rename = "@timestamp")]
     #[serde(with = "logstash_date_format")]
@@ -19,6 +19,30 @@ pub struct LogStashRecord {
     pub fields: HashMap<String, Value>,
 }
 
+/// Where key-value pairs of `log` records are stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyValuePlacement {
+    /// Top-level fields, keys equal to names of record fields get `kv_` prefix
+    #[default]
+    TopLevel,
+    /// Top-level fields with names starting with the prefix
+    Prefix(String),
+    /// Fields of the nested object
+    Object(String),
+}
+
+#[cfg(feature = "kv")]
+const RESERVED_FIELDS: &[&str] = &[
+    "@timestamp",
+    "module",
+    "file",
+    "line",
+    "level",
+    "target",
+    "message",
+];
+
 impl Default for LogStashRecord {
     fn default() -> Self {
         Self {
@@ -42,7 +66,26 @@ impl LogStashRecord {
         }
     }
 
+    /// Converts the record, key-value pairs are stored as top-level fields with `kv` feature.
     pub fn from_record(record: &log::Record) -> Self {
+        #[allow(unused_mut)]
+        let mut event = Self::from_record_args(record);
+        #[cfg(feature = "kv")]
+        event.add_key_values(record, &KeyValuePlacement::TopLevel);
+        event
+    }
+
+    #[cfg(feature = "kv")]
+    pub fn from_record_with_key_values(
+        record: &log::Record,
+        placement: &KeyValuePlacement,
+    ) -> Self {
+        let mut event = Self::from_record_args(record);
+        event.add_key_values(record, placement);
+        event
+    }
+
+    fn from_record_args(record: &log::Record) -> Self {
         let mut event = LogStashRecord::new();
         let meta = record.metadata();
 
@@ -55,13 +98,82 @@ impl LogStashRecord {
         event
     }
 
+    /// Adds key-value pairs of the record as JSON values, values captured with `:serde` keep
+    /// their structure.
+    #[cfg(feature = "kv")]
+    pub fn add_key_values(&mut self, record: &log::Record, placement: &KeyValuePlacement) {
+        struct Collect<'a>(&'a mut serde_json::Map<String, Value>);
+        impl<'kvs> log::kv::VisitSource<'kvs> for Collect<'_> {
+            fn visit_pair(
+                &mut self,
+                key: log::kv::Key<'kvs>,
+                value: log::kv::Value<'kvs>,
+            ) -> Result<(), log::kv::Error> {
+                let value = serde_json::to_value(&value)
+                    .unwrap_or_else(|_| Value::String(value.to_string()));
+                self.0.insert(key.as_str().to_string(), value);
+                Ok(())
+            }
+        }
+
+        let mut pairs = serde_json::Map::new();
+        let _ = record.key_values().visit(&mut Collect(&mut pairs));
+        if pairs.is_empty() {
+            return;
+        }
+        match placement {
+            KeyValuePlacement::TopLevel => {
+                for (key, value) in pairs {
+                    let key = if RESERVED_FIELDS.contains(&key.as_str()) {
+                        format!("kv_{}", key)
+                    } else {
+                        key
+                    };
+                    self.fields.insert(key, value);
+                }
+            }
+            KeyValuePlacement::Prefix(prefix) => {
+                for (key, value) in pairs {
+                    self.fields.insert(format!("{}{}", prefix, key), value);
+                }
+            }
+            KeyValuePlacement::Object(name) => match self.fields.get_mut(name) {
+                Some(Value::Object(object)) => object.extend(pairs),
+                _ => {
+                    self.fields.insert(name.clone(), Value::Object(pairs));
+                }
+            },
+        }
+    }
+
     pub fn set_timestamp(&mut self, timestamp: SystemTime) -> &mut Self {
         self.timestamp = timestamp.into();
         self
     }
 
+    /// Sets the value in the `@metadata` object, which Logstash does not send to outputs.
+    /// Dots in the key denote nested objects as in [`set_path`](Self::set_path).
     pub fn add_metadata(&mut self, key: &str, value: Value) -> &mut Self {
-        self.fields.insert(format!("@metadata.{}", key), value);
+        self.set_path(&format!("@metadata.{}", key), value)
+    }
+
+    /// Sets the value at the dot separated path, e.g. `http.request.method`. Missing objects
+    /// on the path are created, non-object values on the path are replaced. An object value is
+    /// merged into the existing object.
+    pub fn set_path(&mut self, path: &str, value: Value) -> &mut Self {
+        let mut keys = path.split('.');
+        let first = keys.next().unwrap_or_default();
+        let mut target = self.fields.entry(first.to_string()).or_insert(Value::Null);
+        for key in keys {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            target = match target {
+                Value::Object(object) => object.entry(key).or_insert(Value::Null),
+                _ => unreachable!("replaced with object above"),
+            };
+        }
+        merge_value(target, value);
         self
     }
 
@@ -70,6 +182,23 @@ impl LogStashRecord {
         self
     }
 
+    /// Length of the record serialized as JSON, used as size estimate by byte limits
+    pub fn serialized_len(&self) -> usize {
+        struct Counter(usize);
+        impl std::io::Write for Counter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += buf.len();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut counter = Counter(0);
+        let _ = serde_json::to_writer(&mut counter, self);
+        counter.0
+    }
+
     pub fn with_data_from_map(mut self, extra_fields: &HashMap<String, Value>) -> Self {
         if !extra_fields.is_empty() {
             self.fields.extend(
@@ -82,9 +211,20 @@ impl LogStashRecord {
     }
 }
 
+fn merge_value(target: &mut Value, value: Value) {
+    match (target, value) {
+        (Value::Object(target), Value::Object(value)) => {
+            for (key, value) in value {
+                merge_value(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, value) => *target = value,
+    }
+}
+
 mod logstash_date_format {
     use chrono::{DateTime, Utc};
-    use serde::{self, Serializer};
+    use serde::{self, Deserialize, Deserializer, Serializer};
 
     pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -93,11 +233,21 @@ mod logstash_date_format {
         let s = date.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
         serializer.serialize_str(&s)
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&s)
+            .map(|date| date.with_timezone(&Utc))
+            .map_err(serde::de::Error::custom)
+    }
 }
 
 mod level_serializer {
     use log::Level;
-    use serde::{self, Serializer};
+    use serde::{self, Deserialize, Deserializer, Serializer};
 
     pub fn serialize<S>(level: &Level, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -105,4 +255,12 @@ mod level_serializer {
     {
         serializer.serialize_str(level.as_str())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Level, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
 }
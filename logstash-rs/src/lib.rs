@@ -1,11 +1,46 @@
+#[cfg(feature = "tokio")]
+pub mod async_buffer;
 pub mod buffer;
+pub mod dedup;
+pub mod ecs;
 pub mod error;
+pub mod error_handler;
 pub mod event;
+pub mod limits;
+pub mod metrics;
 pub mod output;
-pub use buffer::BufferedSender;
+pub mod parallel;
+pub mod processor;
+pub mod queue;
+pub mod rate_limit;
+pub mod redact;
+pub mod retry;
+pub mod sampling;
+pub mod spool;
+#[cfg(feature = "tokio")]
+pub use async_buffer::{AsyncBufferedSender, AsyncBufferedSenderBuilder};
+pub use buffer::{BufferedSender, BufferedSenderBuilder, OversizedPolicy, RestartPolicy};
+pub use dedup::DedupConfig;
+pub use ecs::{EcsConfig, OutputSchema, ECS_VERSION};
 pub use error::Error;
-pub use event::LogStashRecord;
-pub use output::tcp::TcpSender;
+pub use error_handler::{ErrorEvent, ErrorHandler, PrintErrorHandler};
+pub use event::{KeyValuePlacement, LogStashRecord};
+pub use limits::{SizeLimits, TRUNCATED_TAG};
+pub use metrics::{DropReason, Metrics, MetricsSnapshot};
+#[cfg(feature = "tokio")]
+pub use output::async_tcp::AsyncTcpSender;
+pub use output::codec::{CborCodec, Codec, FnCodec, JsonArrayCodec, JsonLinesCodec, MsgpackCodec};
+pub use output::compression::{Compression, CompressionAlgorithm};
+pub use output::proxy::{Proxy, ProxyKind};
+pub use output::tcp::{TcpSender, TlsBackend};
+pub use parallel::{ParallelSender, WorkerOrdering};
+pub use processor::{Condition, Processor, ProcessorChain};
+pub use queue::OverflowPolicy;
+pub use rate_limit::{RateLimit, RateLimitConfig};
+pub use redact::{RedactionConfig, Redactor, Replacement};
+pub use retry::{RetryConfig, RetryDropPolicy};
+pub use sampling::{SamplingConfig, SAMPLE_RATE_FIELD};
+pub use spool::{FsyncPolicy, SpoolConfig};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -13,6 +48,23 @@ pub trait Sender: Sync + Send + 'static {
     fn send(&self, event: LogStashRecord) -> Result<()>;
     fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()>;
     fn flush(&self) -> Result<()>;
+    /// Sends pending records and releases resources waiting up to the timeout
+    fn shutdown(&self, _timeout: std::time::Duration) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// Asynchronous counterpart of [`Sender`] for use with Tokio (`tokio` feature)
+#[cfg(feature = "tokio")]
+#[async_trait::async_trait]
+pub trait AsyncSender: Sync + Send + 'static {
+    async fn send(&self, event: LogStashRecord) -> Result<()>;
+    async fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()>;
+    async fn flush(&self) -> Result<()>;
+    /// Sends pending records and releases resources waiting up to the timeout
+    async fn shutdown(&self, _timeout: std::time::Duration) -> Result<()> {
+        self.flush().await
+    }
 }
 
 mod prelude {
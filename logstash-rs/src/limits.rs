@@ -0,0 +1,231 @@
+use crate::prelude::*;
+use crate::processor::Processor;
+use serde_json::{Map, Value};
+
+/// Tag added to the `tags` field of records cut by [`SizeLimits`]
+pub const TRUNCATED_TAG: &str = "_truncated";
+
+/// Appended to truncated strings
+pub(crate) const TRUNCATION_MARKER: &str = "...";
+
+/// Limits of record size applied before serialization.
+///
+/// Lengths are in bytes of UTF-8 and include the truncation marker, strings are never cut
+/// inside a character. A record cut by any limit gets [`TRUNCATED_TAG`] in its `tags`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Maximum length of the `message` field, `max_field_len` is used if not set
+    pub max_message_len: Option<usize>,
+    /// Maximum length of other string values at any depth
+    pub max_field_len: Option<usize>,
+    /// Maximum number of entries in the record and in each nested object or array,
+    /// `message` and `tags` of the record are always kept and counted
+    pub max_fields: Option<usize>,
+    /// Objects and arrays nested deeper are replaced by their JSON text, top-level fields
+    /// have depth 1
+    pub max_depth: Option<usize>,
+}
+
+impl SizeLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = Some(len);
+        self
+    }
+
+    pub fn with_max_field_len(mut self, len: usize) -> Self {
+        self.max_field_len = Some(len);
+        self
+    }
+
+    pub fn with_max_fields(mut self, count: usize) -> Self {
+        self.max_fields = Some(count);
+        self
+    }
+
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Cuts the record to the limits, returns `true` if anything was cut
+    pub fn apply(&self, event: &mut LogStashRecord) -> bool {
+        let mut truncated = false;
+        for (key, value) in event.fields.iter_mut() {
+            let max_len = match key.as_str() {
+                "message" => self.max_message_len.or(self.max_field_len),
+                _ => self.max_field_len,
+            };
+            truncated |= self.limit_value(value, 1, max_len);
+        }
+        if let Some(max_fields) = self.max_fields {
+            // Cut record gets `tags` if it has none
+            let added_tags = truncated && !event.fields.contains_key("tags");
+            if event.fields.len() + added_tags as usize > max_fields {
+                let mut keys: Vec<_> = event
+                    .fields
+                    .keys()
+                    .filter(|key| *key != "message" && *key != "tags")
+                    .cloned()
+                    .collect();
+                keys.sort_unstable();
+                let reserved = 1 + event.fields.contains_key("message") as usize;
+                for key in keys.into_iter().skip(max_fields.saturating_sub(reserved)) {
+                    event.fields.remove(&key);
+                }
+                truncated = true;
+            }
+        }
+        if truncated {
+            add_tag(event, TRUNCATED_TAG);
+        }
+        truncated
+    }
+
+    fn limit_value(&self, value: &mut Value, depth: usize, max_len: Option<usize>) -> bool {
+        let nested = matches!(value, Value::Object(_) | Value::Array(_));
+        if nested && self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            *value = Value::String(value.to_string());
+            self.limit_value(value, depth, max_len);
+            return true;
+        }
+        match value {
+            Value::String(s) => max_len.is_some_and(|max_len| truncate_str(s, max_len)),
+            Value::Array(items) => {
+                let mut truncated = self.limit_len(items.len());
+                if truncated {
+                    items.truncate(self.max_fields.unwrap_or(usize::MAX));
+                }
+                for item in items.iter_mut() {
+                    truncated |= self.limit_value(item, depth + 1, self.max_field_len);
+                }
+                truncated
+            }
+            Value::Object(map) => self.limit_map(map, depth + 1),
+            _ => false,
+        }
+    }
+
+    fn limit_map(&self, map: &mut Map<String, Value>, depth: usize) -> bool {
+        let mut truncated = self.limit_len(map.len());
+        if truncated {
+            let keep = self.max_fields.unwrap_or(usize::MAX);
+            let removed: Vec<_> = map.keys().skip(keep).cloned().collect();
+            for key in removed {
+                map.remove(&key);
+            }
+        }
+        for value in map.values_mut() {
+            truncated |= self.limit_value(value, depth, self.max_field_len);
+        }
+        truncated
+    }
+
+    fn limit_len(&self, len: usize) -> bool {
+        self.max_fields.is_some_and(|max_fields| len > max_fields)
+    }
+}
+
+impl Processor for SizeLimits {
+    fn process(&self, mut event: LogStashRecord) -> Option<LogStashRecord> {
+        self.apply(&mut event);
+        Some(event)
+    }
+}
+
+/// Cuts the string to `max_len` bytes ending with the marker, returns `true` if it was cut
+pub(crate) fn truncate_str(s: &mut String, max_len: usize) -> bool {
+    if s.len() <= max_len {
+        return false;
+    }
+    let marker = if max_len >= TRUNCATION_MARKER.len() {
+        TRUNCATION_MARKER
+    } else {
+        ""
+    };
+    let mut len = max_len - marker.len();
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    s.truncate(len);
+    s.push_str(marker);
+    true
+}
+
+pub(crate) fn add_tag(event: &mut LogStashRecord, tag: &str) {
+    let tags = event
+        .fields
+        .entry("tags".to_string())
+        .or_insert_with(|| Value::Array(vec![]));
+    if let Value::String(single) = tags {
+        *tags = Value::Array(vec![Value::String(std::mem::take(single))]);
+    }
+    match tags {
+        Value::Array(tags) if !tags.iter().any(|t| t == tag) => tags.push(tag.into()),
+        Value::Array(_) => {}
+        other => *other = Value::Array(vec![other.take(), tag.into()]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record(fields: Value) -> LogStashRecord {
+        let mut event = LogStashRecord::new();
+        if let Value::Object(fields) = fields {
+            event.fields.extend(fields);
+        }
+        event
+    }
+
+    #[test]
+    fn record_within_limits_is_not_changed() {
+        let limits = SizeLimits::new().with_max_field_len(10).with_max_fields(5);
+        let mut event = record(json!({"message": "short", "a": 1}));
+        assert!(!limits.apply(&mut event));
+        assert!(!event.fields.contains_key("tags"));
+    }
+
+    #[test]
+    fn strings_are_cut_at_char_boundary() {
+        let limits = SizeLimits::new()
+            .with_max_message_len(8)
+            .with_max_field_len(5);
+        let mut event = record(json!({"message": "abcdéfgh", "nested": {"field": "ééé"}}));
+        assert!(limits.apply(&mut event));
+        // `é` takes two bytes and doesn't fit before the marker
+        assert_eq!(event.fields["message"], "abcd...");
+        assert_eq!(event.fields["nested"]["field"], "é...");
+        assert_eq!(event.fields["tags"], json!([TRUNCATED_TAG]));
+    }
+
+    #[test]
+    fn extra_fields_are_removed_keeping_message_and_tags() {
+        let limits = SizeLimits::new().with_max_fields(3);
+        let mut event = record(json!({
+            "message": "m",
+            "tags": "existing",
+            "a": 1,
+            "b": 2,
+            "list": [1, 2, 3, 4],
+        }));
+        assert!(limits.apply(&mut event));
+        assert_eq!(event.fields.len(), 3);
+        assert_eq!(event.fields["a"], 1);
+        assert_eq!(event.fields["tags"], json!(["existing", TRUNCATED_TAG]));
+    }
+
+    #[test]
+    fn deep_values_are_replaced_by_json_text() {
+        let limits = SizeLimits::new().with_max_depth(1);
+        let mut event = record(json!({"a": {"b": {"c": 1}}, "list": [[1]]}));
+        assert!(limits.apply(&mut event));
+        assert_eq!(event.fields["a"]["b"], r#"{"c":1}"#);
+        assert_eq!(event.fields["list"][0], "[1]");
+    }
+}
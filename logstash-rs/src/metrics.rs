@@ -0,0 +1,195 @@
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Why records were dropped without being sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// Log queue was full
+    QueueFull,
+    /// Evicted from the log queue by `DropOldest` overflow policy
+    Evicted,
+    /// Exceeded batch size limit
+    Oversized,
+    /// Removed from full retry queue
+    RetryOverflow,
+    /// Removed from full spool
+    SpoolOverflow,
+    /// Sending failed and the record wasn't kept for retry
+    SendFailed,
+    /// Suppressed by rate limiter
+    RateLimited,
+    /// Dropped by sampling
+    Sampled,
+    /// Counted in `repeat_count` of a record with the same template
+    Collapsed,
+}
+
+impl DropReason {
+    const ALL: [DropReason; 9] = [
+        DropReason::QueueFull,
+        DropReason::Evicted,
+        DropReason::Oversized,
+        DropReason::RetryOverflow,
+        DropReason::SpoolOverflow,
+        DropReason::SendFailed,
+        DropReason::RateLimited,
+        DropReason::Sampled,
+        DropReason::Collapsed,
+    ];
+}
+
+/// Point-in-time copy of sender metrics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Records accepted into the log queue
+    pub enqueued: u64,
+    /// Records written to the connection
+    pub sent: u64,
+    /// Dropped records by reason
+    pub dropped: HashMap<DropReason, u64>,
+    /// Payloads written to the connection
+    pub batches: u64,
+    /// Bytes written to the connection (after compression)
+    pub bytes_written: u64,
+    /// Connections established after the first one
+    pub reconnects: u64,
+    /// Restarts of the sender thread after fatal errors
+    pub restarts: u64,
+    /// Internal errors by [`Error::kind`](crate::Error::kind)
+    pub errors: HashMap<&'static str, u64>,
+    /// Records waiting in the log queue
+    pub queue_depth: usize,
+    /// Time of the last successful write
+    pub last_send: Option<SystemTime>,
+}
+
+impl MetricsSnapshot {
+    /// Number of dropped records for all reasons
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped.values().sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    enqueued: AtomicU64,
+    sent: AtomicU64,
+    dropped: [AtomicU64; DropReason::ALL.len()],
+    batches: AtomicU64,
+    bytes_written: AtomicU64,
+    reconnects: AtomicU64,
+    restarts: AtomicU64,
+    errors: Mutex<HashMap<&'static str, u64>>,
+    last_send: Mutex<Option<SystemTime>>,
+}
+
+/// Metrics collected by senders.
+///
+/// The handle is cheap to clone, pass the same handle to [`TcpSender`](crate::TcpSender) and
+/// [`BufferedSender`](crate::BufferedSender) to collect all metrics in one place.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current values, `queue_depth` is filled by `BufferedSender`
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = &*self.counters;
+        MetricsSnapshot {
+            enqueued: counters.enqueued.load(Ordering::Relaxed),
+            sent: counters.sent.load(Ordering::Relaxed),
+            dropped: DropReason::ALL
+                .iter()
+                .zip(&counters.dropped)
+                .map(|(reason, count)| (*reason, count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            batches: counters.batches.load(Ordering::Relaxed),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+            reconnects: counters.reconnects.load(Ordering::Relaxed),
+            restarts: counters.restarts.load(Ordering::Relaxed),
+            errors: counters
+                .errors
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            queue_depth: 0,
+            last_send: *counters.last_send.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+
+    /// Number of dropped records for all reasons
+    pub fn dropped_total(&self) -> u64 {
+        self.counters
+            .dropped
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub(crate) fn add_enqueued(&self, records: usize) {
+        self.counters
+            .enqueued
+            .fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_sent(&self, records: usize, bytes: usize) {
+        let counters = &*self.counters;
+        counters.sent.fetch_add(records as u64, Ordering::Relaxed);
+        counters.batches.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        *counters.last_send.lock().unwrap_or_else(|e| e.into_inner()) = Some(SystemTime::now());
+    }
+
+    pub(crate) fn add_dropped(&self, reason: DropReason, records: usize) {
+        if records == 0 {
+            return;
+        }
+        let index = DropReason::ALL
+            .iter()
+            .position(|r| *r == reason)
+            .expect("all reasons are listed");
+        self.counters.dropped[index].fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_reconnect(&self) {
+        self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_restart(&self) {
+        self.counters.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_error(&self, err: &Error) {
+        let mut errors = self
+            .counters
+            .errors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *errors.entry(err.kind()).or_default() += 1;
+    }
+}
+
+/// Periodic metrics reporting of the sender thread
+pub(crate) struct MetricsReporter {
+    pub(crate) interval: Duration,
+    pub(crate) callback: Box<dyn Fn(&MetricsSnapshot) + Send + Sync>,
+}
+
+impl std::fmt::Debug for MetricsReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricsReporter")
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
@@ -0,0 +1,214 @@
+use super::compression::Compression;
+use crate::error_handler;
+use crate::prelude::*;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_crate::io::{AsyncWrite, AsyncWriteExt};
+use tokio_crate::net::TcpStream;
+use tokio_crate::sync::Mutex;
+
+type Stream = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// Asynchronous counterpart of [`TcpSender`](crate::TcpSender) running on Tokio.
+///
+/// TLS connections are made with `tokio-rustls` (`tokio-rustls` feature), proxies are not
+/// supported.
+pub struct AsyncTcpSender {
+    hostname: String,
+    port: u16,
+    use_tls: bool,
+    connection_timeout: Option<Duration>,
+    compression: Option<Compression>,
+    redactor: Option<Redactor>,
+    limits: Option<SizeLimits>,
+    codec: Arc<dyn Codec>,
+    stream: Mutex<Option<Stream>>,
+    metrics: Metrics,
+    connected: AtomicBool,
+}
+
+impl AsyncTcpSender {
+    pub fn new(
+        hostname: String,
+        port: u16,
+        use_tls: bool,
+        connection_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            hostname,
+            port,
+            use_tls,
+            connection_timeout,
+            compression: None,
+            redactor: None,
+            limits: None,
+            codec: Arc::new(JsonLinesCodec::new()),
+            stream: Mutex::new(None),
+            metrics: Metrics::default(),
+            connected: AtomicBool::new(false),
+        }
+    }
+
+    /// Compresses every sent payload. Sends fail if the algorithm feature is not enabled, see
+    /// [`Compression::check`].
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Masks or hashes sensitive values before records are serialized.
+    pub fn with_redaction(mut self, redaction: RedactionConfig) -> Self {
+        self.redactor = Some(Redactor::new(redaction));
+        self
+    }
+
+    /// Truncates records exceeding the limits before they are serialized, applied after
+    /// redaction.
+    pub fn with_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Sets encoding of sent records, [`JsonLinesCodec`] by default.
+    pub fn with_codec(mut self, codec: impl Codec) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Collects sent records, written bytes and reconnects into the metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    async fn send_payload(&self, payload: &[u8], records: usize) -> Result<()> {
+        let compressed;
+        let payload = match &self.compression {
+            Some(compression) => {
+                compressed = compression.compress(payload)?;
+                &compressed
+            }
+            None => payload,
+        };
+        let mut stream = self.stream.lock().await;
+        let should_repeat = self.send_bytes(&mut stream, payload).await?;
+        if should_repeat {
+            self.send_bytes(&mut stream, payload).await?;
+        }
+        self.metrics.add_sent(records, payload.len());
+        Ok(())
+    }
+
+    /// Returns `true` if the write to an old connection failed and should be repeated
+    async fn send_bytes(&self, stream: &mut Option<Stream>, bytes: &[u8]) -> Result<bool> {
+        let recreated = self.recreate_stream_if_needed(stream).await?;
+        let conn = stream.as_mut().expect("should be some");
+        let result = match conn.write_all(bytes).await {
+            Ok(()) => conn.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            *stream = None;
+            if !recreated {
+                return Ok(true);
+            }
+            return Err(err.into());
+        }
+        Ok(false)
+    }
+
+    async fn recreate_stream_if_needed(&self, stream: &mut Option<Stream>) -> Result<bool> {
+        if stream.is_some() {
+            return Ok(false);
+        }
+        *stream = Some(if self.use_tls {
+            self.create_tls_connection().await?
+        } else {
+            Box::new(self.create_connection().await?)
+        });
+        if self.connected.swap(true, Ordering::Relaxed) {
+            self.metrics.add_reconnect();
+        }
+        Ok(true)
+    }
+
+    async fn create_connection(&self) -> Result<TcpStream> {
+        let connect = TcpStream::connect((self.hostname.as_str(), self.port));
+        let stream = match self.connection_timeout {
+            Some(timeout) => tokio_crate::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))??,
+            None => connect.await?,
+        };
+        Ok(stream)
+    }
+
+    #[cfg(feature = "tokio-rustls")]
+    async fn create_tls_connection(&self) -> Result<Stream> {
+        use std::convert::TryInto;
+        let connector = tokio_rustls_crate::TlsConnector::from(super::tcp::rustls_config());
+        let server_name = self.hostname.as_str().try_into()?;
+        let stream = connector
+            .connect(server_name, self.create_connection().await?)
+            .await?;
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(feature = "tokio-rustls"))]
+    async fn create_tls_connection(&self) -> Result<Stream> {
+        Err(Error::TlsBackendUnavailable("tokio-rustls"))
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncSender for AsyncTcpSender {
+    async fn send(&self, mut event: LogStashRecord) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        if let Some(redactor) = &self.redactor {
+            redactor.redact(&mut event);
+        }
+        if let Some(limits) = &self.limits {
+            limits.apply(&mut event);
+        }
+        let mut buf = vec![];
+        self.codec.frame(std::slice::from_ref(&event), &mut buf)?;
+        self.send_payload(&buf, 1).await
+    }
+
+    async fn send_batch(&self, mut events: Vec<LogStashRecord>) -> Result<()> {
+        if events.is_empty() || error_handler::is_reporting() {
+            return Ok(());
+        }
+        if let Some(redactor) = &self.redactor {
+            events.iter_mut().for_each(|event| redactor.redact(event));
+        }
+        if let Some(limits) = &self.limits {
+            events.iter_mut().for_each(|event| {
+                limits.apply(event);
+            });
+        }
+        let mut buf = vec![];
+        self.codec.frame(&events, &mut buf)?;
+        self.send_payload(&buf, events.len()).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let mut stream = self.stream.lock().await;
+        let recreated = self.recreate_stream_if_needed(&mut stream).await?;
+        if !recreated {
+            if let Err(err) = stream.as_mut().expect("should be some").flush().await {
+                *stream = None;
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
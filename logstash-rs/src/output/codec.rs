@@ -0,0 +1,279 @@
+use crate::ecs::OutputSchema;
+use crate::prelude::*;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Encodes records into payloads sent by network senders.
+///
+/// `frame` builds the payload of a batch, which is compressed and written as a whole. A single
+/// record is sent as a batch of one.
+pub trait Codec: Send + Sync + 'static {
+    /// Appends the encoded record to the buffer
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()>;
+
+    /// Appends the encoded batch to the buffer, records follow each other by default
+    fn frame(&self, events: &[LogStashRecord], buf: &mut Vec<u8>) -> Result<()> {
+        for event in events {
+            self.encode(event, buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<C: Codec + ?Sized> Codec for Arc<C> {
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()> {
+        (**self).encode(event, buf)
+    }
+
+    fn frame(&self, events: &[LogStashRecord], buf: &mut Vec<u8>) -> Result<()> {
+        (**self).frame(events, buf)
+    }
+}
+
+/// Record in the layout of the schema
+enum Document<'a> {
+    Record(&'a LogStashRecord),
+    Value(Value),
+}
+
+impl<'a> Document<'a> {
+    fn new(schema: &OutputSchema, event: &'a LogStashRecord) -> Self {
+        match schema {
+            OutputSchema::Legacy => Document::Record(event),
+            OutputSchema::Ecs(config) => Document::Value(event.to_ecs(config)),
+        }
+    }
+}
+
+impl Serialize for Document<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Document::Record(event) => event.serialize(serializer),
+            Document::Value(value) => value.serialize(serializer),
+        }
+    }
+}
+
+/// JSON object per line, the format expected by `json_lines` codec of Logstash.
+#[derive(Debug, Clone, Default)]
+pub struct JsonLinesCodec {
+    schema: OutputSchema,
+}
+
+impl JsonLinesCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_schema(mut self, schema: OutputSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+}
+
+impl Codec for JsonLinesCodec {
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()> {
+        serde_json::to_writer(&mut *buf, &Document::new(&self.schema, event))?;
+        buf.push(b'\n');
+        Ok(())
+    }
+}
+
+/// JSON array of records per batch followed by a newline.
+#[derive(Debug, Clone, Default)]
+pub struct JsonArrayCodec {
+    schema: OutputSchema,
+}
+
+impl JsonArrayCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_schema(mut self, schema: OutputSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+}
+
+impl Codec for JsonArrayCodec {
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()> {
+        serde_json::to_writer(&mut *buf, &Document::new(&self.schema, event))?;
+        Ok(())
+    }
+
+    fn frame(&self, events: &[LogStashRecord], buf: &mut Vec<u8>) -> Result<()> {
+        buf.push(b'[');
+        for (i, event) in events.iter().enumerate() {
+            if i > 0 {
+                buf.push(b',');
+            }
+            self.encode(event, buf)?;
+        }
+        buf.extend_from_slice(b"]\n");
+        Ok(())
+    }
+}
+
+/// MessagePack maps following each other (`msgpack` feature), the format expected by
+/// `msgpack` codec of Logstash.
+#[derive(Debug, Clone, Default)]
+pub struct MsgpackCodec {
+    schema: OutputSchema,
+}
+
+impl MsgpackCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_schema(mut self, schema: OutputSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+}
+
+impl Codec for MsgpackCodec {
+    #[cfg(feature = "msgpack")]
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()> {
+        rmp_serde::encode::write_named(buf, &Document::new(&self.schema, event))
+            .map_err(|err| Error::Encode(err.to_string()))
+    }
+
+    #[cfg(not(feature = "msgpack"))]
+    fn encode(&self, _event: &LogStashRecord, _buf: &mut Vec<u8>) -> Result<()> {
+        Err(Error::CodecUnavailable("msgpack"))
+    }
+}
+
+/// CBOR maps following each other (`cbor` feature).
+#[derive(Debug, Clone, Default)]
+pub struct CborCodec {
+    schema: OutputSchema,
+}
+
+impl CborCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_schema(mut self, schema: OutputSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+}
+
+impl Codec for CborCodec {
+    #[cfg(feature = "cbor")]
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()> {
+        ciborium::ser::into_writer(&Document::new(&self.schema, event), buf)
+            .map_err(|err| Error::Encode(err.to_string()))
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    fn encode(&self, _event: &LogStashRecord, _buf: &mut Vec<u8>) -> Result<()> {
+        Err(Error::CodecUnavailable("cbor"))
+    }
+}
+
+/// Codec encoding each record with the closure.
+pub struct FnCodec<F> {
+    encode: F,
+}
+
+impl<F> FnCodec<F>
+where
+    F: Fn(&LogStashRecord, &mut Vec<u8>) -> Result<()> + Send + Sync + 'static,
+{
+    pub fn new(encode: F) -> Self {
+        Self { encode }
+    }
+}
+
+impl<F> Codec for FnCodec<F>
+where
+    F: Fn(&LogStashRecord, &mut Vec<u8>) -> Result<()> + Send + Sync + 'static,
+{
+    fn encode(&self, event: &LogStashRecord, buf: &mut Vec<u8>) -> Result<()> {
+        (self.encode)(event, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<LogStashRecord> {
+        ["m0", "m1"]
+            .iter()
+            .map(|message| {
+                let mut event = LogStashRecord::new();
+                event.add_data("message", (*message).into());
+                event
+            })
+            .collect()
+    }
+
+    fn frame(codec: &impl Codec) -> Vec<u8> {
+        let mut buf = vec![];
+        codec.frame(&records(), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn json_lines_frame_has_record_per_line() {
+        let buf = String::from_utf8(frame(&JsonLinesCodec::new())).unwrap();
+        let lines: Vec<Value> = buf
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(buf.ends_with('\n'));
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["message"], "m1");
+    }
+
+    #[test]
+    fn json_array_frame_is_one_array() {
+        let buf = frame(&JsonArrayCodec::new());
+        assert!(buf.ends_with(b"]\n"));
+        let array: Vec<Value> = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(array.len(), 2);
+        assert_eq!(array[0]["message"], "m0");
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_records_are_decodable_maps() {
+        let buf = frame(&MsgpackCodec::new());
+        let mut reader = &buf[..];
+        let first: Value = rmp_serde::from_read(&mut reader).unwrap();
+        let second: Value = rmp_serde::from_read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(first["message"], "m0");
+        assert_eq!(
+            second["level"],
+            records()[1].level.to_string().to_uppercase()
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_records_are_decodable_maps() {
+        let buf = frame(&CborCodec::new());
+        let mut reader = &buf[..];
+        let first: Value = ciborium::de::from_reader(&mut reader).unwrap();
+        let second: Value = ciborium::de::from_reader(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(first["message"], "m0");
+        assert_eq!(second["message"], "m1");
+    }
+
+    #[cfg(not(feature = "msgpack"))]
+    #[test]
+    fn msgpack_is_unavailable_without_feature() {
+        let mut buf = vec![];
+        let result = MsgpackCodec::new().encode(&records()[0], &mut buf);
+        assert!(matches!(result, Err(Error::CodecUnavailable("msgpack"))));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::prelude::*;
+
+/// Compression algorithm applied to the payload sent by network senders.
+///
+/// Every payload is compressed independently, so TCP stream is a sequence of concatenated
+/// compressed frames (compatible with `gzip_lines`-style codecs for `Gzip`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionAlgorithm {
+    /// Gzip (`gzip` feature)
+    Gzip,
+    /// Zlib (`gzip` feature)
+    Zlib,
+    /// Zstandard (`zstd` feature)
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// Name of the cargo feature which enables this algorithm
+    pub fn feature(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip | CompressionAlgorithm::Zlib => "gzip",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+
+    /// Whether the feature of this algorithm is enabled
+    pub fn is_available(&self) -> bool {
+        match self {
+            CompressionAlgorithm::Gzip | CompressionAlgorithm::Zlib => cfg!(feature = "gzip"),
+            CompressionAlgorithm::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Value of `Content-Encoding` header for HTTP senders
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gzip",
+            CompressionAlgorithm::Zlib => "deflate",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+}
+
+/// Payload compression settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    /// Compression level, algorithm default is used if not set.
+    /// Valid range is 0-9 for `Gzip`/`Zlib` and 1-22 for `Zstd`.
+    #[serde(default)]
+    pub level: Option<u32>,
+}
+
+impl Compression {
+    pub fn new(algorithm: CompressionAlgorithm) -> Self {
+        Self {
+            algorithm,
+            level: None,
+        }
+    }
+
+    /// Sets compression level
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Fails with [`Error::CompressionUnavailable`] if the algorithm feature is not enabled
+    pub fn check(&self) -> Result<()> {
+        if self.algorithm.is_available() {
+            Ok(())
+        } else {
+            Err(Error::CompressionUnavailable(self.algorithm.feature()))
+        }
+    }
+
+    /// Compresses payload into single self-contained frame
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.algorithm {
+            CompressionAlgorithm::Gzip | CompressionAlgorithm::Zlib => self.compress_flate(data),
+            CompressionAlgorithm::Zstd => self.compress_zstd(data),
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    fn compress_flate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        use std::io::Write;
+        let level = self
+            .level
+            .map(|level| flate2::Compression::new(level.min(9)))
+            .unwrap_or_default();
+        let buf = Vec::with_capacity(data.len() / 4);
+        let buf = if self.algorithm == CompressionAlgorithm::Gzip {
+            let mut encoder = flate2::write::GzEncoder::new(buf, level);
+            encoder.write_all(data)?;
+            encoder.finish()?
+        } else {
+            let mut encoder = flate2::write::ZlibEncoder::new(buf, level);
+            encoder.write_all(data)?;
+            encoder.finish()?
+        };
+        Ok(buf)
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    fn compress_flate(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::CompressionUnavailable(self.algorithm.feature()))
+    }
+
+    #[cfg(feature = "zstd")]
+    fn compress_zstd(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // Level 0 selects zstd default level
+        let level = self.level.map(|level| level.min(22) as i32).unwrap_or(0);
+        Ok(zstd_crate::bulk::compress(data, level)?)
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn compress_zstd(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::CompressionUnavailable(self.algorithm.feature()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unavailable_algorithm_is_rejected() {
+        for algorithm in [
+            CompressionAlgorithm::Gzip,
+            CompressionAlgorithm::Zlib,
+            CompressionAlgorithm::Zstd,
+        ]
+        .iter()
+        {
+            let compression = Compression::new(*algorithm);
+            match compression.check() {
+                Ok(()) => assert!(compression.compress(b"record").is_ok()),
+                Err(Error::CompressionUnavailable(feature)) => {
+                    assert_eq!(feature, algorithm.feature());
+                    assert!(!algorithm.is_available());
+                }
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+}
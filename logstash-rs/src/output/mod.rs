@@ -1 +1,23 @@
+#[cfg(feature = "tokio")]
+pub mod async_tcp;
+pub mod codec;
+pub mod compression;
+pub mod proxy;
 pub mod tcp;
+
+use crate::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub(crate) fn connect(hostname: &str, port: u16, timeout: Option<Duration>) -> Result<TcpStream> {
+    let addr = (hostname, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::AddressResolution(hostname.to_string(), port))?;
+    let stream = if let Some(timeout) = timeout {
+        TcpStream::connect_timeout(&addr, timeout)?
+    } else {
+        TcpStream::connect(addr)?
+    };
+    Ok(stream)
+}
@@ -0,0 +1,437 @@
+use super::connect;
+use crate::prelude::*;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Ipv6Addr, TcpStream};
+use std::time::Duration;
+
+/// Proxy protocol used to tunnel connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    /// HTTP proxy with `CONNECT` method support
+    Http,
+    /// SOCKS5 proxy (RFC 1928), target hostname is resolved by the proxy
+    Socks5,
+}
+
+/// Proxy server settings.
+///
+/// Connections to the target are tunneled through the proxy, TLS (if enabled) is layered on
+/// top of the tunnel.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub hostname: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Password is not printed to keep it out of logs
+        f.debug_struct("Proxy")
+            .field("kind", &self.kind)
+            .field("hostname", &self.hostname)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl Proxy {
+    pub fn new(kind: ProxyKind, hostname: impl Into<String>, port: u16) -> Self {
+        Self {
+            kind,
+            hostname: hostname.into(),
+            port,
+            username: None,
+            password: None,
+        }
+    }
+
+    /// Sets credentials for proxy authentication
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Opens connection to the proxy and establishes tunnel to `hostname:port`.
+    pub fn connect(
+        &self,
+        hostname: &str,
+        port: u16,
+        timeout: Option<Duration>,
+    ) -> Result<TcpStream> {
+        let mut stream = connect(&self.hostname, self.port, timeout)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        match self.kind {
+            ProxyKind::Http => self.http_connect(&mut stream, hostname, port)?,
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, hostname, port)?,
+        }
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, hostname: &str, port: u16) -> Result<()> {
+        // IPv6 address is enclosed in brackets in authority form
+        let host = match hostname.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{}]", hostname),
+            Err(_) => hostname.to_string(),
+        };
+        let mut request = format!(
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+            host = host,
+            port = port
+        );
+        if let Some(username) = &self.username {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                self.password.as_deref().unwrap_or_default()
+            );
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(credentials)
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Read byte by byte to not consume any data of the tunneled connection
+        let mut response = Vec::with_capacity(128);
+        let mut byte = [0u8];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 8192 {
+                return Err(Error::Proxy("HTTP proxy response is too long".into()));
+            }
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1);
+        match status {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(Error::Proxy(format!(
+                "HTTP proxy CONNECT failed: {}",
+                status_line
+            ))),
+        }
+    }
+
+    fn socks5_connect(&self, stream: &mut TcpStream, hostname: &str, port: u16) -> Result<()> {
+        const VERSION: u8 = 5;
+        const NO_AUTH: u8 = 0;
+        const USER_PASS_AUTH: u8 = 2;
+
+        if self.username.is_some() {
+            stream.write_all(&[VERSION, 2, NO_AUTH, USER_PASS_AUTH])?;
+        } else {
+            stream.write_all(&[VERSION, 1, NO_AUTH])?;
+        }
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(Error::Proxy(format!(
+                "unsupported SOCKS version {}",
+                reply[0]
+            )));
+        }
+        match reply[1] {
+            NO_AUTH => {}
+            USER_PASS_AUTH if self.username.is_some() => self.socks5_authenticate(stream)?,
+            _ => {
+                return Err(Error::Proxy(
+                    "SOCKS5 proxy rejected authentication methods".into(),
+                ))
+            }
+        }
+
+        let hostname = hostname.as_bytes();
+        if hostname.len() > u8::MAX as usize {
+            return Err(Error::Proxy("hostname is too long for SOCKS5".into()));
+        }
+        let mut request = vec![VERSION, 1, 0, 3, hostname.len() as u8];
+        request.extend_from_slice(hostname);
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(Error::Proxy(format!(
+                "SOCKS5 connect failed with code {}",
+                reply[1]
+            )));
+        }
+        let addr_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            atyp => {
+                return Err(Error::Proxy(format!(
+                    "unknown SOCKS5 address type {}",
+                    atyp
+                )))
+            }
+        };
+        // Bound address and port are not used
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(())
+    }
+
+    fn socks5_authenticate(&self, stream: &mut TcpStream) -> Result<()> {
+        let username = self.username.as_deref().unwrap_or_default().as_bytes();
+        let password = self.password.as_deref().unwrap_or_default().as_bytes();
+        if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+            return Err(Error::Proxy("SOCKS5 credentials are too long".into()));
+        }
+        let mut request = vec![1, username.len() as u8];
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(Error::Proxy("SOCKS5 authentication failed".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    /// Accepts one connection and serves it with the closure, returns the proxy port
+    fn stub<T, F>(serve: F) -> (u16, JoinHandle<T>)
+    where
+        T: Send + 'static,
+        F: FnOnce(TcpStream) -> T + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(TIMEOUT).unwrap();
+            serve(stream)
+        });
+        (port, handle)
+    }
+
+    /// Reads the request head and sends the response, returns the request lines
+    fn http_stub(response: &'static str) -> (u16, JoinHandle<Vec<String>>) {
+        stub(move |mut stream| {
+            let mut lines = vec![];
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                lines.push(line.trim_end().to_string());
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            lines
+        })
+    }
+
+    fn read_vec(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    /// Reads SOCKS5 connect request, returns target host and port
+    fn read_socks5_request(stream: &mut TcpStream) -> (String, u16) {
+        assert_eq!(read_vec(stream, 4), [5, 1, 0, 3]);
+        let len = read_vec(stream, 1)[0] as usize;
+        let host = String::from_utf8(read_vec(stream, len)).unwrap();
+        let port = read_vec(stream, 2);
+        (host, u16::from_be_bytes([port[0], port[1]]))
+    }
+
+    const SOCKS5_SUCCESS: [u8; 10] = [5, 0, 0, 1, 127, 0, 0, 1, 0, 80];
+
+    #[test]
+    fn http_connect_establishes_tunnel() {
+        let (port, handle) =
+            http_stub("HTTP/1.1 200 Connection established\r\nVia: stub\r\n\r\ntunneled");
+        let proxy = Proxy::new(ProxyKind::Http, "127.0.0.1", port);
+        let mut stream = proxy.connect("logstash.local", 5044, TIMEOUT).unwrap();
+
+        let request = handle.join().unwrap();
+        assert_eq!(request[0], "CONNECT logstash.local:5044 HTTP/1.1");
+        assert!(request.contains(&"Host: logstash.local:5044".to_string()));
+        assert!(!request.iter().any(|l| l.starts_with("Proxy-Authorization")));
+        // Data after the response head belongs to the tunnel
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "tunneled");
+    }
+
+    #[test]
+    fn http_connect_encloses_ipv6_target_in_brackets() {
+        let (port, handle) = http_stub("HTTP/1.1 200 OK\r\n\r\n");
+        let proxy = Proxy::new(ProxyKind::Http, "127.0.0.1", port);
+        proxy.connect("::1", 5044, TIMEOUT).unwrap();
+
+        let request = handle.join().unwrap();
+        assert_eq!(request[0], "CONNECT [::1]:5044 HTTP/1.1");
+        assert!(request.contains(&"Host: [::1]:5044".to_string()));
+    }
+
+    #[test]
+    fn debug_hides_password() {
+        let proxy = Proxy::new(ProxyKind::Http, "proxy", 3128).with_credentials("user", "secret");
+        let debug = format!("{:?}", proxy);
+        assert!(
+            debug.contains("user") && !debug.contains("secret"),
+            "{}",
+            debug
+        );
+    }
+
+    #[test]
+    fn http_connect_sends_basic_credentials() {
+        let (port, handle) = http_stub("HTTP/1.1 200 OK\r\n\r\n");
+        let proxy = Proxy::new(ProxyKind::Http, "127.0.0.1", port).with_credentials("user", "pass");
+        proxy.connect("logstash.local", 5044, TIMEOUT).unwrap();
+
+        let request = handle.join().unwrap();
+        assert!(request.contains(&"Proxy-Authorization: Basic dXNlcjpwYXNz".to_string()));
+    }
+
+    #[test]
+    fn http_connect_fails_on_auth_required() {
+        let (port, handle) = http_stub(
+            "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n",
+        );
+        let proxy = Proxy::new(ProxyKind::Http, "127.0.0.1", port);
+        let err = proxy.connect("logstash.local", 5044, TIMEOUT).unwrap_err();
+
+        handle.join().unwrap();
+        match err {
+            Error::Proxy(message) => assert!(message.contains("407"), "{}", message),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn socks5_connects_without_auth() {
+        let (port, handle) = stub(|mut stream| {
+            assert_eq!(read_vec(&mut stream, 3), [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+            let target = read_socks5_request(&mut stream);
+            stream.write_all(&SOCKS5_SUCCESS).unwrap();
+            stream.write_all(b"tunneled").unwrap();
+            target
+        });
+        let proxy = Proxy::new(ProxyKind::Socks5, "127.0.0.1", port);
+        let mut stream = proxy.connect("logstash.local", 5044, TIMEOUT).unwrap();
+
+        assert_eq!(handle.join().unwrap(), ("logstash.local".to_string(), 5044));
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "tunneled");
+    }
+
+    #[test]
+    fn socks5_authenticates_with_password() {
+        let (port, handle) = stub(|mut stream| {
+            assert_eq!(read_vec(&mut stream, 4), [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).unwrap();
+            assert_eq!(read_vec(&mut stream, 2), [1, 4]);
+            assert_eq!(read_vec(&mut stream, 4), b"user");
+            assert_eq!(read_vec(&mut stream, 1), [4]);
+            assert_eq!(read_vec(&mut stream, 4), b"pass");
+            stream.write_all(&[1, 0]).unwrap();
+            let target = read_socks5_request(&mut stream);
+            // Bound address as a domain name
+            stream.write_all(&[5, 0, 0, 3, 5]).unwrap();
+            stream.write_all(b"proxy\x00\x50").unwrap();
+            target
+        });
+        let proxy =
+            Proxy::new(ProxyKind::Socks5, "127.0.0.1", port).with_credentials("user", "pass");
+        proxy.connect("logstash.local", 5044, TIMEOUT).unwrap();
+
+        assert_eq!(handle.join().unwrap(), ("logstash.local".to_string(), 5044));
+    }
+
+    #[test]
+    fn socks5_fails_on_rejected_auth() {
+        let (port, handle) = stub(|mut stream| {
+            read_vec(&mut stream, 4);
+            stream.write_all(&[5, 2]).unwrap();
+            read_vec(&mut stream, 2 + 4 + 1 + 5);
+            stream.write_all(&[1, 1]).unwrap();
+        });
+        let proxy =
+            Proxy::new(ProxyKind::Socks5, "127.0.0.1", port).with_credentials("user", "wrong");
+        let err = proxy.connect("logstash.local", 5044, TIMEOUT).unwrap_err();
+
+        handle.join().unwrap();
+        assert!(matches!(err, Error::Proxy(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn socks5_fails_on_rejected_method() {
+        let (port, handle) = stub(|mut stream| {
+            read_vec(&mut stream, 3);
+            stream.write_all(&[5, 0xff]).unwrap();
+        });
+        let proxy = Proxy::new(ProxyKind::Socks5, "127.0.0.1", port);
+        let err = proxy.connect("logstash.local", 5044, TIMEOUT).unwrap_err();
+
+        handle.join().unwrap();
+        match err {
+            Error::Proxy(message) => assert!(message.contains("authentication methods")),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn socks5_fails_on_rejected_connect() {
+        let (port, handle) = stub(|mut stream| {
+            read_vec(&mut stream, 3);
+            stream.write_all(&[5, 0]).unwrap();
+            read_socks5_request(&mut stream);
+            // Connection refused
+            stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        });
+        let proxy = Proxy::new(ProxyKind::Socks5, "127.0.0.1", port);
+        let err = proxy.connect("logstash.local", 5044, TIMEOUT).unwrap_err();
+
+        handle.join().unwrap();
+        match err {
+            Error::Proxy(message) => assert!(message.contains("code 5"), "{}", message),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
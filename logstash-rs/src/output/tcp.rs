@@ -1,19 +1,79 @@
+use super::compression::Compression;
+use super::connect;
+use super::proxy::Proxy;
+use crate::error_handler;
 use crate::prelude::*;
-use std::fmt::Write as FMTWrite;
 use std::io::Write as IOWrite;
 use std::net::TcpStream;
-use std::net::ToSocketAddrs;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 type Stream = Box<dyn IOWrite + Sync + Send>;
 
+/// TLS implementation used for encrypted connections.
+///
+/// Both backends may be compiled in at the same time, the one to use is selected at runtime.
+/// Selecting a backend whose feature is not enabled results in
+/// [`Error::TlsBackendUnavailable`](crate::Error::TlsBackendUnavailable) on connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsBackend {
+    /// Platform TLS library via `native-tls` (`tls` feature)
+    NativeTls,
+    /// Pure Rust TLS via `rustls` (`rustls` feature)
+    Rustls,
+}
+
+impl TlsBackend {
+    /// Name of the cargo feature which enables this backend
+    pub fn feature(&self) -> &'static str {
+        match self {
+            TlsBackend::NativeTls => "tls",
+            TlsBackend::Rustls => "rustls",
+        }
+    }
+}
+
+impl Default for TlsBackend {
+    /// `NativeTls` if `tls` feature is enabled, `Rustls` otherwise
+    fn default() -> Self {
+        if cfg!(feature = "tls") || !cfg!(feature = "rustls") {
+            TlsBackend::NativeTls
+        } else {
+            TlsBackend::Rustls
+        }
+    }
+}
+
+/// Client config trusting `webpki-roots` certificates
+#[cfg(feature = "rustls")]
+pub(crate) fn rustls_config() -> std::sync::Arc<rustls_crate::ClientConfig> {
+    let mut root_store = rustls_crate::RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls_crate::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = rustls_crate::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    std::sync::Arc::new(config)
+}
+
 pub(crate) struct AdvancedTcpStream {
     hostname: String,
     port: u16,
     use_tls: bool,
+    tls_backend: TlsBackend,
+    proxy: Option<Proxy>,
     stream: Mutex<Option<Stream>>,
     connection_timeout: Option<Duration>,
+    metrics: Metrics,
+    connected: AtomicBool,
 }
 
 impl AdvancedTcpStream {
@@ -27,8 +87,12 @@ impl AdvancedTcpStream {
             hostname,
             port,
             use_tls,
+            tls_backend: TlsBackend::default(),
+            proxy: None,
             stream: Mutex::new(None),
             connection_timeout,
+            metrics: Metrics::default(),
+            connected: AtomicBool::new(false),
         }
     }
 
@@ -60,6 +124,9 @@ impl AdvancedTcpStream {
             } else {
                 self.create_tcp_connection()?
             });
+            if self.connected.swap(true, Ordering::Relaxed) {
+                self.metrics.add_reconnect();
+            }
             Ok(true)
         } else {
             Ok(false)
@@ -67,30 +134,25 @@ impl AdvancedTcpStream {
     }
 
     fn create_connection(&self) -> Result<TcpStream> {
-        let addr = (self.hostname.as_str(), self.port)
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| Error::AddressResolution(self.hostname.clone(), self.port))?;
-        let stream = if let Some(timeout) = self.connection_timeout {
-            TcpStream::connect_timeout(&addr, timeout)?
-        } else {
-            TcpStream::connect(addr)?
-        };
-        Ok(stream)
+        match &self.proxy {
+            Some(proxy) => proxy.connect(&self.hostname, self.port, self.connection_timeout),
+            None => connect(&self.hostname, self.port, self.connection_timeout),
+        }
     }
 
     fn create_tcp_connection(&self) -> Result<Stream> {
         Ok(Box::new(self.create_connection()?))
     }
 
-    #[cfg(all(feature = "tls", feature = "rustls"))]
     fn create_tls_connection(&self) -> Result<Stream> {
-        compile_error!("Select one of 'tls' or 'rustls' feature");
-        unreachable!();
+        match self.tls_backend {
+            TlsBackend::NativeTls => self.create_native_tls_connection(),
+            TlsBackend::Rustls => self.create_rustls_connection(),
+        }
     }
 
-    #[cfg(all(feature = "tls", not(feature = "rustls")))]
-    fn create_tls_connection(&self) -> Result<Stream> {
+    #[cfg(feature = "tls")]
+    fn create_native_tls_connection(&self) -> Result<Stream> {
         use native_tls::HandshakeError;
         let conn = native_tls::TlsConnector::new()?;
         let stream = self.create_connection()?;
@@ -106,24 +168,18 @@ impl AdvancedTcpStream {
         Ok(Box::new(stream.expect("handshake completed")))
     }
 
-    #[cfg(all(not(feature = "tls"), feature = "rustls"))]
-    fn create_tls_connection(&self) -> Result<Stream> {
+    #[cfg(not(feature = "tls"))]
+    fn create_native_tls_connection(&self) -> Result<Stream> {
+        Err(Error::TlsBackendUnavailable(
+            TlsBackend::NativeTls.feature(),
+        ))
+    }
+
+    #[cfg(feature = "rustls")]
+    fn create_rustls_connection(&self) -> Result<Stream> {
         use std::convert::TryInto;
-        use std::sync::Arc;
-        let mut root_store = rustls_crate::RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            rustls_crate::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-        let config = rustls_crate::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
         let session = rustls_crate::ClientConnection::new(
-            Arc::new(config),
+            rustls_config(),
             self.hostname.as_str().try_into()?,
         )?;
         let stream = self.create_connection()?;
@@ -131,9 +187,9 @@ impl AdvancedTcpStream {
         Ok(Box::new(stream))
     }
 
-    #[cfg(all(not(feature = "tls"), not(feature = "rustls")))]
-    fn create_tls_connection(&self) -> Result<Stream> {
-        panic!("TLS is not supported. Please enable 'tls' feature")
+    #[cfg(not(feature = "rustls"))]
+    fn create_rustls_connection(&self) -> Result<Stream> {
+        Err(Error::TlsBackendUnavailable(TlsBackend::Rustls.feature()))
     }
 
     fn flush(&self) -> Result<()> {
@@ -148,6 +204,10 @@ impl AdvancedTcpStream {
 
 pub struct TcpSender {
     stream: AdvancedTcpStream,
+    compression: Option<Compression>,
+    redactor: Option<Redactor>,
+    limits: Option<SizeLimits>,
+    codec: Arc<dyn Codec>,
 }
 
 impl TcpSender {
@@ -159,28 +219,108 @@ impl TcpSender {
     ) -> Self {
         Self {
             stream: AdvancedTcpStream::new(hostname, port, use_tls, connection_timeout),
+            compression: None,
+            redactor: None,
+            limits: None,
+            codec: Arc::new(JsonLinesCodec::new()),
         }
     }
+
+    /// Sets TLS implementation used when `use_tls` is enabled.
+    pub fn with_tls_backend(mut self, tls_backend: TlsBackend) -> Self {
+        self.stream.tls_backend = tls_backend;
+        self
+    }
+
+    /// Tunnels connections through HTTP CONNECT or SOCKS5 proxy.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.stream.proxy = Some(proxy);
+        self
+    }
+
+    /// Compresses every sent payload. Sends fail if the algorithm feature is not enabled, see
+    /// [`Compression::check`].
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Masks or hashes sensitive values before records are serialized.
+    pub fn with_redaction(mut self, redaction: RedactionConfig) -> Self {
+        self.redactor = Some(Redactor::new(redaction));
+        self
+    }
+
+    /// Truncates records exceeding the limits before they are serialized, applied after
+    /// redaction.
+    pub fn with_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Sets encoding of sent records, [`JsonLinesCodec`] by default.
+    pub fn with_codec(mut self, codec: impl Codec) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Collects sent records, written bytes and reconnects into the metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.stream.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.stream.metrics.snapshot()
+    }
+
+    fn send_payload(&self, payload: &[u8], records: usize) -> Result<()> {
+        let compressed;
+        let payload = match &self.compression {
+            Some(compression) => {
+                compressed = compression.compress(payload)?;
+                &compressed
+            }
+            None => payload,
+        };
+        self.stream.send_bytes(payload)?;
+        self.stream.metrics.add_sent(records, payload.len());
+        Ok(())
+    }
 }
 
 impl Sender for TcpSender {
-    fn send(&self, event: LogStashRecord) -> Result<()> {
-        let mut event = serde_json::to_string(&event)?;
-        event.write_char('\n')?;
-        self.stream.send_bytes(event.as_bytes())?;
+    fn send(&self, mut event: LogStashRecord) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        if let Some(redactor) = &self.redactor {
+            redactor.redact(&mut event);
+        }
+        if let Some(limits) = &self.limits {
+            limits.apply(&mut event);
+        }
+        let mut buf = vec![];
+        self.codec.frame(std::slice::from_ref(&event), &mut buf)?;
+        self.send_payload(&buf, 1)?;
         Ok(())
     }
 
-    fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()> {
-        if events.is_empty() {
+    fn send_batch(&self, mut events: Vec<LogStashRecord>) -> Result<()> {
+        if events.is_empty() || error_handler::is_reporting() {
             return Ok(());
         }
-        let mut buf = vec![];
-        for event in events {
-            serde_json::to_writer(&mut buf, &event)?;
-            buf.push('\n' as u8);
+        if let Some(redactor) = &self.redactor {
+            events.iter_mut().for_each(|event| redactor.redact(event));
         }
-        self.stream.send_bytes(&buf)?;
+        if let Some(limits) = &self.limits {
+            events.iter_mut().for_each(|event| {
+                limits.apply(event);
+            });
+        }
+        let mut buf = vec![];
+        self.codec.frame(&events, &mut buf)?;
+        self.send_payload(&buf, events.len())?;
         Ok(())
     }
 
@@ -0,0 +1,315 @@
+use crate::error_handler;
+use crate::prelude::*;
+use crate::queue::mark_delivering_thread;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How records are distributed between connections of [`ParallelSender`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerOrdering {
+    /// Batches are split into equal parts, records of different parts may be reordered
+    #[default]
+    Unordered,
+    /// Records with the same target always use the same connection and keep their order
+    PerTarget,
+}
+
+enum Job {
+    Batch(Vec<LogStashRecord>),
+    Flush,
+    Shutdown(Duration),
+}
+
+struct Worker {
+    jobs: mpsc::Sender<(u64, Job)>,
+    /// Results tagged with the sequence number of their job
+    results: mpsc::Receiver<(u64, Result<()>)>,
+    next_seq: AtomicU64,
+    /// Records of the batch being sent by the thread
+    pending: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    fn spawn<S: Sender>(sender: S) -> Self {
+        let (jobs, job_receiver) = mpsc::channel();
+        let (result_sender, results) = mpsc::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let thread_pending = pending.clone();
+        // The thread stops when the pool is dropped
+        std::thread::spawn(move || {
+            mark_delivering_thread();
+            for (seq, job) in job_receiver {
+                let (result, stop) = match job {
+                    Job::Batch(events) => {
+                        let len = events.len();
+                        let result = sender.send_batch(events);
+                        thread_pending.fetch_sub(len, Ordering::Relaxed);
+                        (result, false)
+                    }
+                    Job::Flush => (sender.flush(), false),
+                    Job::Shutdown(timeout) => (sender.shutdown(timeout), true),
+                };
+                if result_sender.send((seq, result)).is_err() || stop {
+                    break;
+                }
+            }
+        });
+        Self {
+            jobs,
+            results,
+            next_seq: AtomicU64::new(0),
+            pending,
+        }
+    }
+
+    /// Returns the sequence number to wait for
+    fn start(&self, job: Job) -> Result<u64> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let len = match &job {
+            Job::Batch(events) => events.len(),
+            _ => 0,
+        };
+        self.pending.fetch_add(len, Ordering::Relaxed);
+        self.jobs.send((seq, job)).map_err(|err| {
+            self.pending.fetch_sub(len, Ordering::Relaxed);
+            Error::SenderThreadStopped(format!("worker: {}", err))
+        })?;
+        Ok(seq)
+    }
+
+    /// Waits for the result of the job, results of jobs that timed out before are skipped
+    fn wait(&self, seq: u64, timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let (result_seq, result) = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    self.results
+                        .recv_timeout(timeout)
+                        .map_err(|err| match err {
+                            mpsc::RecvTimeoutError::Timeout => {
+                                Error::ShutdownTimeout(self.pending.load(Ordering::Relaxed))
+                            }
+                            mpsc::RecvTimeoutError::Disconnected => worker_stopped(),
+                        })?
+                }
+                None => self.results.recv().map_err(|_| worker_stopped())?,
+            };
+            if result_seq == seq {
+                return result;
+            }
+        }
+    }
+}
+
+fn worker_stopped() -> Error {
+    Error::FatalInternal("worker thread stopped".to_string())
+}
+
+/// Keeps the first error, records not sent by timed out connections are added up
+fn combine(result: Result<()>, other: Result<()>) -> Result<()> {
+    match (result, other) {
+        (Err(Error::ShutdownTimeout(a)), Err(Error::ShutdownTimeout(b))) => {
+            Err(Error::ShutdownTimeout(a + b))
+        }
+        (result, other) => result.and(other),
+    }
+}
+
+/// Sends records over several connections in parallel.
+///
+/// Every sender but the first one is owned by its own worker thread, the first one is used by
+/// the calling thread. A batch is split between the senders and the call returns when all parts
+/// are sent, so the batch fails if any part fails and may be partially delivered.
+///
+/// Records sent over different connections may be reordered by the receiving side. Batches are
+/// not reordered, each one is sent after the previous one is complete. Use
+/// [`WorkerOrdering::PerTarget`] to keep the order of records with the same target.
+pub struct ParallelSender<S: Sender> {
+    local: S,
+    workers: Mutex<Vec<Worker>>,
+    ordering: WorkerOrdering,
+    /// Connection for the next single record in unordered mode
+    next: AtomicUsize,
+}
+
+impl<S: Sender> ParallelSender<S> {
+    /// Starts a worker thread for each sender except the first one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `senders` is empty.
+    pub fn new(senders: Vec<S>) -> Self {
+        let mut senders = senders.into_iter();
+        let local = senders.next().expect("at least one sender is required");
+        Self {
+            local,
+            workers: Mutex::new(senders.map(Worker::spawn).collect()),
+            ordering: WorkerOrdering::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_ordering(mut self, ordering: WorkerOrdering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    fn target_index(target: &str, connections: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        target.hash(&mut hasher);
+        (hasher.finish() % connections as u64) as usize
+    }
+
+    fn split(&self, events: Vec<LogStashRecord>, connections: usize) -> Vec<Vec<LogStashRecord>> {
+        let mut parts: Vec<Vec<LogStashRecord>> = (0..connections).map(|_| vec![]).collect();
+        match self.ordering {
+            WorkerOrdering::Unordered => {
+                let part_len = events.len().div_ceil(connections);
+                for (i, event) in events.into_iter().enumerate() {
+                    parts[i / part_len].push(event);
+                }
+            }
+            WorkerOrdering::PerTarget => {
+                for event in events {
+                    parts[Self::target_index(&event.target, connections)].push(event);
+                }
+            }
+        }
+        parts
+    }
+
+    /// Runs the job on every worker and `local` on the calling thread, returns the first error
+    fn run_all(
+        workers: &[Worker],
+        mut make_job: impl FnMut(usize) -> Option<Job>,
+        local: impl FnOnce() -> Result<()>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let mut started = Vec::with_capacity(workers.len());
+        let mut result = Ok(());
+        for (i, worker) in workers.iter().enumerate() {
+            if let Some(job) = make_job(i) {
+                match worker.start(job) {
+                    Ok(seq) => started.push((worker, seq)),
+                    Err(err) => result = result.and(Err(err)),
+                }
+            }
+        }
+        result = combine(result, local());
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        for (worker, seq) in started {
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            result = combine(result, worker.wait(seq, timeout));
+        }
+        result
+    }
+}
+
+impl<S: Sender> Sender for ParallelSender<S> {
+    fn send(&self, event: LogStashRecord) -> Result<()> {
+        if error_handler::is_reporting() {
+            return Ok(());
+        }
+        let workers = self.workers.lock()?;
+        let connections = workers.len() + 1;
+        let index = match self.ordering {
+            WorkerOrdering::Unordered => self.next.fetch_add(1, Ordering::Relaxed) % connections,
+            WorkerOrdering::PerTarget => Self::target_index(&event.target, connections),
+        };
+        match index {
+            0 => self.local.send(event),
+            _ => {
+                let worker = &workers[index - 1];
+                let seq = worker.start(Job::Batch(vec![event]))?;
+                worker.wait(seq, None)
+            }
+        }
+    }
+
+    fn send_batch(&self, events: Vec<LogStashRecord>) -> Result<()> {
+        if events.is_empty() || error_handler::is_reporting() {
+            return Ok(());
+        }
+        let workers = self.workers.lock()?;
+        let mut parts = self.split(events, workers.len() + 1).into_iter();
+        let local = parts.next().expect("at least one part");
+        let mut parts: Vec<_> = parts.collect();
+        Self::run_all(
+            &workers,
+            |i| {
+                Some(std::mem::take(&mut parts[i]))
+                    .filter(|p| !p.is_empty())
+                    .map(Job::Batch)
+            },
+            || self.local.send_batch(local),
+            None,
+        )
+    }
+
+    fn flush(&self) -> Result<()> {
+        let workers = self.workers.lock()?;
+        Self::run_all(&workers, |_| Some(Job::Flush), || self.local.flush(), None)
+    }
+
+    /// Shuts down all senders, the worker threads are stopped
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        let workers = self.workers.lock()?;
+        Self::run_all(
+            &workers,
+            |_| Some(Job::Shutdown(timeout)),
+            || self.local.shutdown(timeout),
+            Some(timeout),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends batches slowly and fails them, flush succeeds
+    struct SlowSender;
+
+    impl Sender for SlowSender {
+        fn send(&self, event: LogStashRecord) -> Result<()> {
+            self.send_batch(vec![event])
+        }
+
+        fn send_batch(&self, _events: Vec<LogStashRecord>) -> Result<()> {
+            std::thread::sleep(Duration::from_millis(200));
+            Err(Error::SenderThreadStopped("slow".to_string()))
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timed_out_result_is_not_returned_for_next_job() {
+        let worker = Worker::spawn(SlowSender);
+        let events = vec![LogStashRecord::new(), LogStashRecord::new()];
+        let seq = worker.start(Job::Batch(events)).unwrap();
+        match worker.wait(seq, Some(Duration::from_millis(10))) {
+            Err(Error::ShutdownTimeout(pending)) => assert_eq!(pending, 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let seq = worker.start(Job::Flush).unwrap();
+        worker.wait(seq, None).unwrap();
+    }
+
+    #[test]
+    fn timed_out_records_are_added_up() {
+        let result = combine(
+            Err(Error::ShutdownTimeout(2)),
+            Err(Error::ShutdownTimeout(3)),
+        );
+        assert!(matches!(result, Err(Error::ShutdownTimeout(5))));
+    }
+}
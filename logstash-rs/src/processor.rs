@@ -0,0 +1,224 @@
+use crate::prelude::*;
+use log::Level;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Transforms records before they are sent, `None` drops the record.
+pub trait Processor: Send + Sync + 'static {
+    fn process(&self, event: LogStashRecord) -> Option<LogStashRecord>;
+}
+
+impl<F> Processor for F
+where
+    F: Fn(LogStashRecord) -> Option<LogStashRecord> + Send + Sync + 'static,
+{
+    fn process(&self, event: LogStashRecord) -> Option<LogStashRecord> {
+        self(event)
+    }
+}
+
+/// Processors applied in order, a dropped record is not passed further.
+#[derive(Default)]
+pub struct ProcessorChain {
+    processors: Vec<Box<dyn Processor>>,
+}
+
+impl ProcessorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the processor to the end of the chain.
+    pub fn with(mut self, processor: impl Processor) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn push(&mut self, processor: Box<dyn Processor>) {
+        self.processors.push(processor);
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl std::fmt::Debug for ProcessorChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ProcessorChain({})", self.processors.len())
+    }
+}
+
+impl Processor for ProcessorChain {
+    fn process(&self, event: LogStashRecord) -> Option<LogStashRecord> {
+        self.processors
+            .iter()
+            .try_fold(event, |event, processor| processor.process(event))
+    }
+}
+
+/// Predicate on a record used by [`DropRecords`] and [`Route`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// Level is this one or more important
+    Level(Level),
+    TargetPrefix(String),
+    FieldExists(String),
+    FieldEquals(String, Value),
+    Not(Box<Condition>),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn matches(&self, event: &LogStashRecord) -> bool {
+        match self {
+            Condition::Level(level) => event.level <= *level,
+            Condition::TargetPrefix(prefix) => event.target.starts_with(prefix.as_str()),
+            Condition::FieldExists(field) => event.fields.contains_key(field),
+            Condition::FieldEquals(field, value) => event.fields.get(field) == Some(value),
+            Condition::Not(condition) => !condition.matches(event),
+            Condition::All(conditions) => conditions.iter().all(|c| c.matches(event)),
+            Condition::Any(conditions) => conditions.iter().any(|c| c.matches(event)),
+        }
+    }
+}
+
+/// Adds fields to every record.
+#[derive(Debug, Clone)]
+pub struct AddFields {
+    fields: HashMap<String, Value>,
+    overwrite: bool,
+}
+
+impl AddFields {
+    pub fn new(fields: HashMap<String, Value>) -> Self {
+        Self {
+            fields,
+            overwrite: true,
+        }
+    }
+
+    /// Replace values of fields which are already set, `true` by default
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+}
+
+impl Processor for AddFields {
+    fn process(&self, mut event: LogStashRecord) -> Option<LogStashRecord> {
+        for (key, value) in &self.fields {
+            if self.overwrite || !event.fields.contains_key(key) {
+                event.fields.insert(key.clone(), value.clone());
+            }
+        }
+        Some(event)
+    }
+}
+
+/// Moves the value of a field to another name.
+#[derive(Debug, Clone)]
+pub struct RenameField {
+    from: String,
+    to: String,
+}
+
+impl RenameField {
+    pub fn new(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+}
+
+impl Processor for RenameField {
+    fn process(&self, mut event: LogStashRecord) -> Option<LogStashRecord> {
+        if let Some(value) = event.fields.remove(&self.from) {
+            event.fields.insert(self.to.clone(), value);
+        }
+        Some(event)
+    }
+}
+
+/// Removes fields from every record.
+#[derive(Debug, Clone)]
+pub struct RemoveFields {
+    fields: Vec<String>,
+}
+
+impl RemoveFields {
+    pub fn new(fields: Vec<String>) -> Self {
+        Self { fields }
+    }
+}
+
+impl Processor for RemoveFields {
+    fn process(&self, mut event: LogStashRecord) -> Option<LogStashRecord> {
+        for field in &self.fields {
+            event.fields.remove(field);
+        }
+        Some(event)
+    }
+}
+
+/// Drops records matching the condition.
+#[derive(Debug, Clone)]
+pub struct DropRecords {
+    condition: Condition,
+}
+
+impl DropRecords {
+    pub fn new(condition: Condition) -> Self {
+        Self { condition }
+    }
+}
+
+impl Processor for DropRecords {
+    fn process(&self, event: LogStashRecord) -> Option<LogStashRecord> {
+        if self.condition.matches(&event) {
+            None
+        } else {
+            Some(event)
+        }
+    }
+}
+
+/// Passes records matching the condition through one chain and other records through another.
+#[derive(Debug)]
+pub struct Route {
+    condition: Condition,
+    matched: ProcessorChain,
+    otherwise: ProcessorChain,
+}
+
+impl Route {
+    pub fn new(condition: Condition, matched: ProcessorChain) -> Self {
+        Self {
+            condition,
+            matched,
+            otherwise: ProcessorChain::new(),
+        }
+    }
+
+    /// Chain for records not matching the condition, they are passed unchanged by default
+    pub fn with_otherwise(mut self, otherwise: ProcessorChain) -> Self {
+        self.otherwise = otherwise;
+        self
+    }
+}
+
+impl Processor for Route {
+    fn process(&self, event: LogStashRecord) -> Option<LogStashRecord> {
+        if self.condition.matches(&event) {
+            self.matched.process(event)
+        } else {
+            self.otherwise.process(event)
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use crate::buffer::Command;
+use log::Level;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::mpsc::{RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What to do with a record when the log queue is full.
+///
+/// Blocking policies act as `DropNewest` on the sender thread and worker threads, so records
+/// they log never wait for the queue they drain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the new record
+    #[default]
+    DropNewest,
+    /// Evict the oldest queued record to make room for the new one
+    DropOldest,
+    /// Wait for free space up to the timeout, then drop the new record
+    Block(Duration),
+    /// Wait for free space as long as needed
+    BlockForever,
+}
+
+/// Overflow policy for each log level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct OverflowPolicies([OverflowPolicy; 5]);
+
+impl OverflowPolicies {
+    pub(crate) fn set_all(&mut self, policy: OverflowPolicy) {
+        self.0 = [policy; 5];
+    }
+
+    pub(crate) fn set(&mut self, level: Level, policy: OverflowPolicy) {
+        self.0[level as usize - 1] = policy;
+    }
+
+    pub(crate) fn get(&self, level: Level) -> OverflowPolicy {
+        self.0[level as usize - 1]
+    }
+}
+
+thread_local! {
+    static DELIVERING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as delivering records. Records logged by such thread, e.g. by TLS
+/// library or error handler, never wait for free space in a queue it may be draining itself.
+pub(crate) fn mark_delivering_thread() {
+    DELIVERING.with(|d| d.set(true));
+}
+
+const HIGH_LANE: usize = 0;
+const NORMAL_LANE: usize = 1;
+
+struct State {
+    /// Commands with their serialized size by lane, high priority lane is always dequeued first
+    lanes: [VecDeque<(Command, usize)>; 2],
+    /// Serialized size of records in the queue and held by the sender thread
+    bytes: usize,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: [Condvar; 2],
+    capacity: [usize; 2],
+    /// Records with this level or more important go to the high priority lane
+    priority_level: Option<Level>,
+    /// Limit of `State::bytes` for the normal lane, the high priority lane is bounded only by
+    /// its length so low priority records never crowd it out
+    max_bytes: Option<usize>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // State stays consistent even if other thread panicked while holding the lock
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn records(&self) -> usize {
+        self.lock()
+            .lanes
+            .iter()
+            .flatten()
+            .map(|(command, _)| command.records())
+            .sum()
+    }
+
+    fn lane(&self, command: &Command) -> usize {
+        match (self.priority_level, command.level()) {
+            (Some(priority_level), Some(level)) if level <= priority_level => HIGH_LANE,
+            _ => NORMAL_LANE,
+        }
+    }
+}
+
+/// Bounded command queue between logging threads and the sender thread.
+/// `priority` enables separate lane of given length for records with the level or more
+/// important, `max_bytes` limits serialized size of records in the queue and the buffer.
+pub(crate) fn queue(
+    capacity: usize,
+    priority: Option<(Level, usize)>,
+    max_bytes: Option<usize>,
+) -> (QueueSender, QueueReceiver) {
+    let high_capacity = priority.map(|(_, len)| len).unwrap_or(0);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            lanes: [
+                VecDeque::with_capacity(high_capacity),
+                VecDeque::with_capacity(capacity),
+            ],
+            bytes: 0,
+            sender_closed: false,
+            receiver_closed: false,
+        }),
+        not_empty: Condvar::new(),
+        not_full: [Condvar::new(), Condvar::new()],
+        capacity: [high_capacity.max(1), capacity.max(1)],
+        priority_level: priority.map(|(level, _)| level),
+        max_bytes,
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+pub(crate) struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    /// Pushes command applying `policy` if the queue is full.
+    /// Returns commands evicted from the queue by `DropOldest` policy, the rejected command is
+    /// dropped on error.
+    pub(crate) fn push(
+        &self,
+        command: Command,
+        policy: OverflowPolicy,
+    ) -> Result<Vec<Command>, TrySendError<()>> {
+        let policy = match policy {
+            OverflowPolicy::Block(_) | OverflowPolicy::BlockForever
+                if DELIVERING.with(Cell::get) =>
+            {
+                OverflowPolicy::DropNewest
+            }
+            policy => policy,
+        };
+        let shared = &self.shared;
+        let lane = shared.lane(&command);
+        let size = match shared.max_bytes {
+            Some(_) => command.serialized_len(),
+            None => 0,
+        };
+        let mut state = shared.lock();
+        let deadline = match policy {
+            OverflowPolicy::Block(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        let mut evicted = vec![];
+        loop {
+            if state.receiver_closed {
+                return Err(TrySendError::Disconnected(()));
+            }
+            let fits = match shared.max_bytes {
+                Some(_) if lane == HIGH_LANE => true,
+                // Single record is accepted regardless of its size
+                Some(max_bytes) => state.bytes == 0 || state.bytes + size <= max_bytes,
+                None => true,
+            };
+            if state.lanes[lane].len() < shared.capacity[lane] && fits {
+                break;
+            }
+            match policy {
+                OverflowPolicy::DropNewest => return Err(TrySendError::Full(())),
+                OverflowPolicy::DropOldest => {
+                    let oldest = state.lanes[lane].iter().position(|(c, _)| c.is_record());
+                    match oldest.and_then(|i| state.lanes[lane].remove(i)) {
+                        Some((oldest, oldest_size)) => {
+                            state.bytes -= oldest_size;
+                            evicted.push(oldest);
+                        }
+                        None => return Err(TrySendError::Full(())),
+                    }
+                }
+                OverflowPolicy::Block(_) => {
+                    let timeout = deadline
+                        .expect("set for block policy")
+                        .saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(TrySendError::Full(()));
+                    }
+                    state = shared.not_full[lane]
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+                OverflowPolicy::BlockForever => {
+                    state = shared.not_full[lane]
+                        .wait(state)
+                        .unwrap_or_else(|e| e.into_inner());
+                }
+            }
+        }
+        state.bytes += size;
+        state.lanes[lane].push_back((command, size));
+        shared.not_empty.notify_one();
+        Ok(evicted)
+    }
+
+    /// Number of records in the queue
+    pub(crate) fn records(&self) -> usize {
+        self.shared.records()
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.shared.lock().sender_closed = true;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+pub(crate) struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl QueueReceiver {
+    /// Waits for the next command, `None` timeout waits forever.
+    /// Returns command with its serialized size which should be released after processing.
+    pub(crate) fn recv_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(Command, usize), RecvTimeoutError> {
+        let shared = &self.shared;
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = shared.lock();
+        loop {
+            for lane in [HIGH_LANE, NORMAL_LANE] {
+                if let Some(entry) = state.lanes[lane].pop_front() {
+                    shared.not_full[lane].notify_one();
+                    return Ok(entry);
+                }
+            }
+            if state.sender_closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    shared
+                        .not_empty
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => shared
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Number of records in the queue
+    pub(crate) fn records(&self) -> usize {
+        self.shared.records()
+    }
+
+    pub(crate) fn budget(&self) -> Option<QueueBudget> {
+        self.shared.max_bytes.map(|_| QueueBudget {
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+/// Handle to return bytes of processed records to the queue memory budget
+pub(crate) struct QueueBudget {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for QueueBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueBudget")
+            .field("max_bytes", &self.shared.max_bytes)
+            .finish()
+    }
+}
+
+impl QueueBudget {
+    pub(crate) fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let mut state = self.shared.lock();
+        state.bytes = state.bytes.saturating_sub(bytes);
+        drop(state);
+        for not_full in &self.shared.not_full {
+            not_full.notify_all();
+        }
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_closed = true;
+        for not_full in &self.shared.not_full {
+            not_full.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    fn send(message: &str) -> Command {
+        send_level(message, Level::Info)
+    }
+
+    fn send_level(message: &str, level: Level) -> Command {
+        let mut event = LogStashRecord::new();
+        event.level = level;
+        event.add_data("message", message.into());
+        Command::Send(event)
+    }
+
+    fn recv_message(receiver: &QueueReceiver) -> String {
+        match receiver.recv_timeout(Some(Duration::from_secs(1))) {
+            Ok((Command::Send(event), _)) => event.fields["message"].as_str().unwrap().to_string(),
+            _ => panic!("record expected"),
+        }
+    }
+
+    #[test]
+    fn drop_oldest_returns_every_evicted_command() {
+        let (sender, receiver) = queue(10, None, Some(400));
+        for message in ["m0", "m1", "m2"].iter() {
+            assert!(sender
+                .push(send(message), OverflowPolicy::DropOldest)
+                .unwrap()
+                .is_empty());
+        }
+
+        let large = send(&"x".repeat(250));
+        let evicted = sender.push(large, OverflowPolicy::DropOldest).unwrap();
+        assert_eq!(evicted.len(), 3);
+        assert_eq!(sender.records(), 1);
+        drop(receiver);
+    }
+
+    #[test]
+    fn drop_newest_rejects_record_when_full() {
+        let (sender, receiver) = queue(2, None, None);
+        for message in ["m0", "m1"].iter() {
+            sender
+                .push(send(message), OverflowPolicy::DropNewest)
+                .unwrap();
+        }
+        let result = sender.push(send("m2"), OverflowPolicy::DropNewest);
+        assert!(matches!(result, Err(TrySendError::Full(()))));
+        assert_eq!(recv_message(&receiver), "m0");
+        assert_eq!(recv_message(&receiver), "m1");
+    }
+
+    #[test]
+    fn block_waits_for_free_space_up_to_timeout() {
+        let (sender, receiver) = queue(1, None, None);
+        sender.push(send("m0"), OverflowPolicy::DropNewest).unwrap();
+
+        let timeout = Duration::from_millis(50);
+        let started = Instant::now();
+        let result = sender.push(send("m1"), OverflowPolicy::Block(timeout));
+        assert!(matches!(result, Err(TrySendError::Full(()))));
+        assert!(started.elapsed() >= timeout);
+
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let message = recv_message(&receiver);
+            (message, receiver)
+        });
+        sender
+            .push(send("m2"), OverflowPolicy::Block(Duration::from_secs(5)))
+            .unwrap();
+        let (message, receiver) = thread.join().unwrap();
+        assert_eq!(message, "m0");
+        assert_eq!(recv_message(&receiver), "m2");
+    }
+
+    #[test]
+    fn priority_lane_is_received_first_and_not_crowded_out() {
+        let (sender, receiver) = queue(1, Some((Level::Warn, 1)), None);
+        sender
+            .push(send("info"), OverflowPolicy::DropNewest)
+            .unwrap();
+        let result = sender.push(send("info2"), OverflowPolicy::DropNewest);
+        assert!(matches!(result, Err(TrySendError::Full(()))));
+        sender
+            .push(
+                send_level("error", Level::Error),
+                OverflowPolicy::DropNewest,
+            )
+            .unwrap();
+
+        assert_eq!(recv_message(&receiver), "error");
+        assert_eq!(recv_message(&receiver), "info");
+    }
+
+    #[test]
+    fn memory_budget_is_released_after_processing() {
+        let size = send("m0").serialized_len();
+        let (sender, receiver) = queue(10, None, Some(2 * size));
+        for message in ["m0", "m1"].iter() {
+            sender
+                .push(send(message), OverflowPolicy::DropNewest)
+                .unwrap();
+        }
+        let result = sender.push(send("m2"), OverflowPolicy::DropNewest);
+        assert!(matches!(result, Err(TrySendError::Full(()))));
+
+        // Received record is still accounted until it is released
+        let (_, received) = receiver.recv_timeout(None).unwrap();
+        let result = sender.push(send("m2"), OverflowPolicy::DropNewest);
+        assert!(matches!(result, Err(TrySendError::Full(()))));
+        receiver.budget().unwrap().release(received);
+        sender.push(send("m2"), OverflowPolicy::DropNewest).unwrap();
+    }
+
+    #[test]
+    fn push_fails_after_receiver_is_dropped() {
+        let (sender, receiver) = queue(1, None, None);
+        drop(receiver);
+        let result = sender.push(send("m0"), OverflowPolicy::BlockForever);
+        assert!(matches!(result, Err(TrySendError::Disconnected(()))));
+    }
+}
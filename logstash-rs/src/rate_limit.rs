@@ -0,0 +1,230 @@
+use crate::prelude::*;
+use log::Level;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Parameters of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub struct RateLimit {
+    /// Records passed per second on average
+    pub rate: f64,
+    /// Maximum number of records passed at once, equal to `rate` if not set
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64) -> Self {
+        Self { rate, burst: None }
+    }
+
+    pub fn with_burst(mut self, burst: f64) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.rate).max(1.0)
+    }
+}
+
+/// Settings of record throttling in front of the log queue.
+///
+/// A record passes if every bucket applicable to it has a token. Suppressed records are
+/// counted per bucket and reported by a synthetic `Warn` record once per `summary_interval`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    /// Limit for all records
+    pub global: Option<RateLimit>,
+    /// Limit for each target separately
+    pub per_target: Option<RateLimit>,
+    /// Limits for records with the level
+    pub per_level: HashMap<Level, RateLimit>,
+    pub summary_interval: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            global: None,
+            per_target: None,
+            per_level: HashMap::new(),
+            summary_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn with_global(mut self, limit: RateLimit) -> Self {
+        self.global = Some(limit);
+        self
+    }
+
+    pub fn with_per_target(mut self, limit: RateLimit) -> Self {
+        self.per_target = Some(limit);
+        self
+    }
+
+    pub fn with_level(mut self, level: Level, limit: RateLimit) -> Self {
+        self.per_level.insert(level, limit);
+        self
+    }
+
+    pub fn with_summary_interval(mut self, interval: Duration) -> Self {
+        self.summary_interval = interval;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.capacity(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.capacity());
+        self.updated = now;
+    }
+}
+
+enum BucketKind {
+    Level,
+    Target,
+    Global,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    global: Option<Bucket>,
+    levels: HashMap<Level, Bucket>,
+    targets: HashMap<String, Bucket>,
+    /// Suppressed records by bucket key since the last summary
+    suppressed: BTreeMap<String, u64>,
+    summary_at: Instant,
+}
+
+/// Token buckets shared by logging threads and the sender thread
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        let state = LimiterState {
+            global: config.global.as_ref().map(|limit| Bucket::new(limit, now)),
+            levels: HashMap::new(),
+            targets: HashMap::new(),
+            suppressed: BTreeMap::new(),
+            summary_at: now + config.summary_interval,
+        };
+        Self {
+            config,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Takes a token from every applicable bucket, returns `false` if the record is suppressed
+    pub(crate) fn check(&self, event: &LogStashRecord) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let LimiterState {
+            global,
+            levels,
+            targets,
+            suppressed,
+            ..
+        } = &mut *state;
+        let mut buckets = Vec::with_capacity(3);
+        if let Some(limit) = self.config.per_level.get(&event.level) {
+            let bucket = levels
+                .entry(event.level)
+                .or_insert_with(|| Bucket::new(limit, now));
+            buckets.push((bucket, limit, BucketKind::Level));
+        }
+        if let Some(limit) = &self.config.per_target {
+            if !targets.contains_key(&event.target) {
+                targets.insert(event.target.clone(), Bucket::new(limit, now));
+            }
+            let bucket = targets.get_mut(&event.target).expect("inserted above");
+            buckets.push((bucket, limit, BucketKind::Target));
+        }
+        if let (Some(bucket), Some(limit)) = (global.as_mut(), &self.config.global) {
+            buckets.push((bucket, limit, BucketKind::Global));
+        }
+        for (bucket, limit, _) in &mut buckets {
+            bucket.refill(limit, now);
+        }
+        // Tokens are taken only if the record passes all buckets
+        let empty = buckets.iter().find(|(bucket, ..)| bucket.tokens < 1.0);
+        let key = match empty.map(|(_, _, kind)| kind) {
+            Some(BucketKind::Level) => format!("level:{}", event.level),
+            Some(BucketKind::Target) => format!("target:{}", event.target),
+            Some(BucketKind::Global) => "global".to_string(),
+            None => {
+                for (bucket, ..) in &mut buckets {
+                    bucket.tokens -= 1.0;
+                }
+                return true;
+            }
+        };
+        *suppressed.entry(key).or_default() += 1;
+        false
+    }
+
+    /// Time of the next summary
+    pub(crate) fn summary_at(&self) -> Instant {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .summary_at
+    }
+
+    /// Record with suppressed counts if the summary interval has passed or `force` is set,
+    /// `None` if nothing was suppressed
+    pub(crate) fn take_summary(&self, force: bool) -> Option<LogStashRecord> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !force && now < state.summary_at {
+            return None;
+        }
+        state.summary_at = now + self.config.summary_interval;
+        if state.suppressed.is_empty() {
+            return None;
+        }
+        let suppressed = std::mem::take(&mut state.suppressed);
+        drop(state);
+        let total: u64 = suppressed.values().sum();
+        let mut event = LogStashRecord::new();
+        event.level = Level::Warn;
+        event.target = module_path!().to_string();
+        event.add_data(
+            "message",
+            format!("rate limit exceeded, {} records suppressed", total).into(),
+        );
+        event.add_data(
+            "suppressed",
+            Value::Object(
+                suppressed
+                    .into_iter()
+                    .map(|(key, count)| (key, count.into()))
+                    .collect(),
+            ),
+        );
+        Some(event)
+    }
+}
@@ -0,0 +1,276 @@
+use crate::prelude::*;
+use crate::processor::Processor;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+pub use regex::Regex;
+
+/// What a redacted value is replaced with.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replacement {
+    /// Fixed text
+    Mask(String),
+    /// `sha256:` followed by the first 16 hex digits of the salted hash, equal values stay
+    /// equal so records can still be correlated
+    Hash,
+}
+
+impl Default for Replacement {
+    fn default() -> Self {
+        Replacement::Mask("[REDACTED]".to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Regex,
+    replacement: Replacement,
+    /// Additional check of a matched value
+    validate: Option<fn(&str) -> bool>,
+}
+
+/// Settings of sensitive data redaction.
+///
+/// Values of fields with names matching a field rule are replaced entirely, including nested
+/// objects and arrays. Parts of string values, including the message, matching a value rule
+/// are replaced in place. Nested objects are checked at any depth.
+#[derive(Debug, Clone, Default)]
+pub struct RedactionConfig {
+    fields: Vec<Rule>,
+    values: Vec<Rule>,
+    /// Salt prepended to values before hashing
+    pub hash_salt: String,
+}
+
+impl RedactionConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redacts values of fields with names matching the pattern.
+    pub fn with_field(mut self, pattern: Regex, replacement: Replacement) -> Self {
+        self.fields.push(Rule {
+            pattern,
+            replacement,
+            validate: None,
+        });
+        self
+    }
+
+    /// Redacts parts of string values matching the pattern.
+    pub fn with_value(mut self, pattern: Regex, replacement: Replacement) -> Self {
+        self.values.push(Rule {
+            pattern,
+            replacement,
+            validate: None,
+        });
+        self
+    }
+
+    /// Adds rules for common secrets: fields named like passwords, tokens, keys, cookies and
+    /// authorization headers, and values looking like card numbers, emails, JWTs and bearer
+    /// tokens.
+    pub fn with_common_rules(mut self, replacement: Replacement) -> Self {
+        let rule = |pattern: &str, validate: Option<fn(&str) -> bool>| Rule {
+            pattern: Regex::new(pattern).expect("valid pattern"),
+            replacement: replacement.clone(),
+            validate,
+        };
+        self.fields.push(rule(
+            r"(?i)passw(or)?d|^pass$|secret|token|authorization|api[-_]?key|cookie|credential",
+            None,
+        ));
+        self.values.extend(vec![
+            rule(
+                r"\b(?:4|5[1-5]|2[2-7]|3[47]|6(?:011|5))(?:[ -]?\d){11,17}\b",
+                Some(luhn_valid),
+            ),
+            rule(
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+                None,
+            ),
+            rule(
+                r"\beyJ[A-Za-z0-9_-]+\.eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*",
+                None,
+            ),
+            rule(r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]+", None),
+        ]);
+        self
+    }
+
+    pub fn with_hash_salt(mut self, salt: impl Into<String>) -> Self {
+        self.hash_salt = salt.into();
+        self
+    }
+}
+
+/// Applies [`RedactionConfig`] to records, usable as a [`Processor`].
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    config: RedactionConfig,
+}
+
+impl Redactor {
+    pub fn new(config: RedactionConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn redact(&self, event: &mut LogStashRecord) {
+        for (key, value) in event.fields.iter_mut() {
+            self.redact_entry(key, value);
+        }
+    }
+
+    fn redact_entry(&self, key: &str, value: &mut Value) {
+        match self.config.fields.iter().find(|r| r.pattern.is_match(key)) {
+            Some(rule) => {
+                let replaced = match &*value {
+                    Value::String(s) => self.replace(&rule.replacement, s),
+                    other => self.replace(&rule.replacement, &other.to_string()),
+                };
+                *value = Value::String(replaced);
+            }
+            None => self.redact_value(value),
+        }
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => self.redact_str(s),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_value(item)),
+            Value::Object(map) => self.redact_map(map),
+            _ => {}
+        }
+    }
+
+    fn redact_map(&self, map: &mut Map<String, Value>) {
+        for (key, value) in map.iter_mut() {
+            self.redact_entry(key, value);
+        }
+    }
+
+    fn redact_str(&self, s: &mut String) {
+        for rule in &self.config.values {
+            let replaced = rule.pattern.replace_all(s, |caps: &regex::Captures| {
+                let matched = &caps[0];
+                match rule.validate {
+                    Some(validate) if !validate(matched) => matched.to_string(),
+                    _ => self.replace(&rule.replacement, matched),
+                }
+            });
+            if let Cow::Owned(replaced) = replaced {
+                *s = replaced;
+            }
+        }
+    }
+
+    fn replace(&self, replacement: &Replacement, value: &str) -> String {
+        match replacement {
+            Replacement::Mask(mask) => mask.clone(),
+            Replacement::Hash => {
+                let mut hasher = Sha256::new();
+                hasher.update(self.config.hash_salt.as_bytes());
+                hasher.update(value.as_bytes());
+                let hash = format!("{:x}", hasher.finalize());
+                format!("sha256:{}", &hash[..16])
+            }
+        }
+    }
+}
+
+impl Processor for Redactor {
+    fn process(&self, mut event: LogStashRecord) -> Option<LogStashRecord> {
+        self.redact(&mut event);
+        Some(event)
+    }
+}
+
+/// Checksum of card numbers, separators are skipped
+fn luhn_valid(number: &str) -> bool {
+    let mut sum = 0;
+    let digits = number.bytes().rev().filter(u8::is_ascii_digit);
+    for (i, digit) in digits.enumerate() {
+        let mut digit = u32::from(digit - b'0');
+        if i % 2 == 1 {
+            digit *= 2;
+            if digit > 9 {
+                digit -= 9;
+            }
+        }
+        sum += digit;
+    }
+    sum % 10 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn redact(config: RedactionConfig, fields: Value) -> Value {
+        let mut event = LogStashRecord::new();
+        if let Value::Object(fields) = fields {
+            event.fields.extend(fields);
+        }
+        Redactor::new(config).redact(&mut event);
+        serde_json::to_value(event.fields).unwrap()
+    }
+
+    #[test]
+    fn matching_fields_are_replaced_at_any_depth() {
+        let config = RedactionConfig::new().with_common_rules(Replacement::default());
+        let fields = redact(
+            config,
+            json!({
+                "password": "p4ss",
+                "request": {"headers": [{"Authorization": {"scheme": "basic"}}], "path": "/"},
+            }),
+        );
+        assert_eq!(fields["password"], "[REDACTED]");
+        assert_eq!(
+            fields["request"]["headers"][0]["Authorization"],
+            "[REDACTED]"
+        );
+        assert_eq!(fields["request"]["path"], "/");
+    }
+
+    #[test]
+    fn matching_values_are_replaced_in_place() {
+        let config = RedactionConfig::new().with_common_rules(Replacement::Mask("*".to_string()));
+        let fields = redact(
+            config,
+            json!({
+                "message": "user a.b@example.com paid with 4111 1111 1111 1111, order 4111111111111112",
+                "header": "Bearer abc.def",
+            }),
+        );
+        assert_eq!(
+            fields["message"],
+            "user * paid with *, order 4111111111111112"
+        );
+        assert_eq!(fields["header"], "*");
+    }
+
+    #[test]
+    fn hashed_values_depend_on_salt() {
+        let config = |salt: &str| {
+            RedactionConfig::new()
+                .with_field(Regex::new("^user$").unwrap(), Replacement::Hash)
+                .with_hash_salt(salt)
+        };
+        let first = redact(config("a"), json!({"user": "alice"}));
+        let second = redact(config("a"), json!({"user": "alice"}));
+        let salted = redact(config("b"), json!({"user": "alice"}));
+
+        let hash = first["user"].as_str().unwrap();
+        assert!(
+            hash.starts_with("sha256:") && hash.len() == 7 + 16,
+            "{}",
+            hash
+        );
+        assert_eq!(first["user"], second["user"]);
+        assert_ne!(first["user"], salted["user"]);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::prelude::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Which records are dropped when retry queue limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryDropPolicy {
+    /// Drop the oldest failed batches to make room for the new one
+    DropOldest,
+    /// Drop the new failed batch
+    DropNewest,
+}
+
+/// Settings of in-memory retry of failed batches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Maximum number of batches waiting for retry
+    pub max_batches: usize,
+    /// Maximum serialized size of records waiting for retry (in bytes)
+    pub max_bytes: usize,
+    /// Delay before the first retry, doubled after every failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub drop_policy: RetryDropPolicy,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_batches: 100,
+            max_bytes: 16 * 1024 * 1024,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            drop_policy: RetryDropPolicy::DropOldest,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn with_max_batches(mut self, max_batches: usize) -> Self {
+        self.max_batches = max_batches;
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_drop_policy(mut self, drop_policy: RetryDropPolicy) -> Self {
+        self.drop_policy = drop_policy;
+        self
+    }
+}
+
+/// Queue of failed batches, retried in order with exponential backoff
+#[derive(Debug)]
+pub(crate) struct RetryQueue {
+    config: RetryConfig,
+    batches: VecDeque<(Vec<LogStashRecord>, usize)>,
+    bytes: usize,
+    records: usize,
+    /// Number of batches ever added and removed, used to track delivery of batches
+    pushed: u64,
+    removed: u64,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl RetryQueue {
+    pub(crate) fn new(config: RetryConfig) -> Self {
+        Self {
+            backoff: config.initial_backoff,
+            config,
+            batches: VecDeque::new(),
+            bytes: 0,
+            records: 0,
+            pushed: 0,
+            removed: 0,
+            retry_at: None,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    /// Number of records waiting for retry
+    pub(crate) fn records(&self) -> usize {
+        self.records
+    }
+
+    /// Position after the last added batch
+    pub(crate) fn mark(&self) -> u64 {
+        self.pushed
+    }
+
+    /// All batches added before the mark were sent or dropped
+    pub(crate) fn is_delivered(&self, mark: u64) -> bool {
+        self.removed >= mark
+    }
+
+    /// Time of the next attempt if there are batches waiting
+    pub(crate) fn next_attempt(&self) -> Option<Instant> {
+        if self.batches.is_empty() {
+            None
+        } else {
+            self.retry_at
+        }
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.retry_at
+            .map(|retry_at| Instant::now() >= retry_at)
+            .unwrap_or(true)
+    }
+
+    /// Adds failed batch to the end of the queue, returns number of dropped records
+    pub(crate) fn push(&mut self, batch: Vec<LogStashRecord>) -> usize {
+        let size = batch.iter().map(|e| e.serialized_len()).sum();
+        let mut dropped = 0;
+        // At least one batch is always kept regardless of its size
+        while !self.batches.is_empty()
+            && (self.batches.len() >= self.config.max_batches
+                || self.bytes + size > self.config.max_bytes)
+        {
+            match self.config.drop_policy {
+                RetryDropPolicy::DropNewest => return batch.len(),
+                RetryDropPolicy::DropOldest => {
+                    let (old, old_size) = self.batches.pop_front().expect("not empty");
+                    self.bytes -= old_size;
+                    self.records -= old.len();
+                    self.removed += 1;
+                    dropped += old.len();
+                }
+            }
+        }
+        self.bytes += size;
+        self.records += batch.len();
+        self.pushed += 1;
+        self.batches.push_back((batch, size));
+        dropped
+    }
+
+    pub(crate) fn front(&self) -> Option<&Vec<LogStashRecord>> {
+        self.batches.front().map(|(batch, _)| batch)
+    }
+
+    /// Removes successfully sent batch from the queue
+    pub(crate) fn pop_front(&mut self) {
+        if let Some((batch, size)) = self.batches.pop_front() {
+            self.bytes -= size;
+            self.records -= batch.len();
+            self.removed += 1;
+        }
+        self.backoff = self.config.initial_backoff;
+        self.retry_at = None;
+    }
+
+    /// Schedules next attempt after failure
+    pub(crate) fn schedule(&mut self) -> Instant {
+        let retry_at = Instant::now() + self.backoff;
+        self.retry_at = Some(retry_at);
+        self.backoff = (self.backoff * 2).min(self.config.max_backoff);
+        retry_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(messages: &[&str]) -> Vec<LogStashRecord> {
+        messages
+            .iter()
+            .map(|message| {
+                let mut event = LogStashRecord::new();
+                event.add_data("message", (*message).into());
+                event
+            })
+            .collect()
+    }
+
+    fn front_messages(queue: &RetryQueue) -> Vec<String> {
+        queue
+            .front()
+            .unwrap()
+            .iter()
+            .map(|e| e.fields["message"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn batches_are_retried_in_order() {
+        let mut queue = RetryQueue::new(RetryConfig::default());
+        assert_eq!(queue.push(batch(&["m0", "m1"])), 0);
+        assert_eq!(queue.push(batch(&["m2"])), 0);
+        assert_eq!(queue.records(), 3);
+        let mark = queue.mark();
+
+        assert_eq!(front_messages(&queue), ["m0", "m1"]);
+        queue.pop_front();
+        assert!(!queue.is_delivered(mark));
+        assert_eq!(front_messages(&queue), ["m2"]);
+        queue.pop_front();
+        assert!(queue.is_delivered(mark));
+        assert!(queue.is_empty());
+        assert_eq!(queue.records(), 0);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_new_batch() {
+        let config = RetryConfig::default().with_max_batches(2);
+        let mut queue = RetryQueue::new(config);
+        queue.push(batch(&["m0", "m1"]));
+        queue.push(batch(&["m2"]));
+        assert_eq!(queue.push(batch(&["m3"])), 2);
+        assert_eq!(queue.records(), 2);
+        assert_eq!(front_messages(&queue), ["m2"]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_queued_batches() {
+        let config = RetryConfig::default()
+            .with_max_batches(2)
+            .with_drop_policy(RetryDropPolicy::DropNewest);
+        let mut queue = RetryQueue::new(config);
+        queue.push(batch(&["m0"]));
+        queue.push(batch(&["m1"]));
+        assert_eq!(queue.push(batch(&["m2", "m3"])), 2);
+        assert_eq!(queue.records(), 2);
+        assert_eq!(front_messages(&queue), ["m0"]);
+    }
+
+    #[test]
+    fn size_limit_keeps_at_least_one_batch() {
+        let size = batch(&["m0"])[0].serialized_len();
+        let config = RetryConfig::default().with_max_bytes(size);
+        let mut queue = RetryQueue::new(config);
+        assert_eq!(queue.push(batch(&["m0", "m1"])), 0);
+        assert_eq!(queue.push(batch(&["m2"])), 2);
+        assert_eq!(front_messages(&queue), ["m2"]);
+    }
+
+    #[test]
+    fn backoff_is_doubled_up_to_max_and_reset_after_success() {
+        let initial = Duration::from_secs(1);
+        let config = RetryConfig::default().with_backoff(initial, Duration::from_secs(3));
+        let mut queue = RetryQueue::new(config);
+        queue.push(batch(&["m0"]));
+        assert!(queue.is_ready());
+
+        for expected in [1, 2, 3, 3].iter() {
+            let now = Instant::now();
+            let retry_at = queue.schedule();
+            let backoff = retry_at - now;
+            assert!(backoff >= Duration::from_secs(*expected), "{:?}", backoff);
+            assert!(backoff < Duration::from_secs(*expected) + initial);
+        }
+        assert!(!queue.is_ready());
+        assert!(queue.next_attempt().is_some());
+
+        queue.pop_front();
+        assert!(queue.is_ready());
+        assert_eq!(queue.next_attempt(), None);
+        assert!(queue.schedule() <= Instant::now() + initial);
+    }
+}
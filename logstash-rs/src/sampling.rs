@@ -0,0 +1,235 @@
+use crate::prelude::*;
+use log::Level;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Name of the field added to records kept by sampling
+pub const SAMPLE_RATE_FIELD: &str = "sample_rate";
+
+/// Settings of record sampling.
+///
+/// Only records with levels listed in `levels` are sampled. A kept record gets
+/// [`SAMPLE_RATE_FIELD`] with the number of records it represents, e.g. `10` for ratio `0.1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingConfig {
+    /// Fraction of records kept for the level
+    pub levels: HashMap<Level, f64>,
+    /// Records are kept or dropped by hash of this field, so records with the same value share
+    /// the decision. Records without the field are sampled randomly.
+    pub key_field: Option<String>,
+    /// Maximum number of sampled records per second, ratios are lowered when it is exceeded
+    pub adaptive_budget: Option<f64>,
+    /// Period of measuring record rate for adaptive sampling
+    pub adaptive_window: Duration,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            levels: HashMap::new(),
+            key_field: None,
+            adaptive_budget: None,
+            adaptive_window: Duration::from_secs(1),
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn with_level(mut self, level: Level, ratio: f64) -> Self {
+        self.levels.insert(level, ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    pub fn with_key_field(mut self, field: impl Into<String>) -> Self {
+        self.key_field = Some(field.into());
+        self
+    }
+
+    pub fn with_adaptive_budget(mut self, records_per_sec: f64) -> Self {
+        self.adaptive_budget = Some(records_per_sec);
+        self
+    }
+
+    pub fn with_adaptive_window(mut self, window: Duration) -> Self {
+        self.adaptive_window = window;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct AdaptiveState {
+    window_start: Instant,
+    /// Records which would be kept in the current window without adaptive factor
+    expected: f64,
+    /// Multiplier of level ratios computed from the previous window
+    factor: f64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    config: SamplingConfig,
+    adaptive: Mutex<AdaptiveState>,
+    random: AtomicU64,
+}
+
+impl Sampler {
+    pub(crate) fn new(config: SamplingConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self {
+            config,
+            adaptive: Mutex::new(AdaptiveState {
+                window_start: Instant::now(),
+                expected: 0.0,
+                factor: 1.0,
+            }),
+            random: AtomicU64::new(seed),
+        }
+    }
+
+    /// Returns `false` if the record is dropped, adds sample rate to kept records
+    pub(crate) fn sample(&self, event: &mut LogStashRecord) -> bool {
+        let ratio = match self.config.levels.get(&event.level) {
+            Some(ratio) => ratio * self.adaptive_factor(*ratio),
+            None => return true,
+        };
+        if ratio >= 1.0 {
+            return true;
+        }
+        if self.point(event) >= ratio {
+            return false;
+        }
+        event.add_data(SAMPLE_RATE_FIELD, (1.0 / ratio).into());
+        true
+    }
+
+    /// Uniform value in `[0, 1)` deciding whether the record is kept
+    fn point(&self, event: &LogStashRecord) -> f64 {
+        let key = self
+            .config
+            .key_field
+            .as_ref()
+            .and_then(|field| event.fields.get(field));
+        let hash = match key {
+            // The hash doesn't depend on the process or Rust version, so all services share
+            // the decision
+            Some(Value::String(s)) => splitmix64(fnv1a(s.as_bytes())),
+            Some(value) => splitmix64(fnv1a(value.to_string().as_bytes())),
+            None => splitmix64(self.random.fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)),
+        };
+        (hash >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn adaptive_factor(&self, ratio: f64) -> f64 {
+        let budget = match self.config.adaptive_budget {
+            Some(budget) => budget,
+            None => return 1.0,
+        };
+        let mut state = self.adaptive.lock().unwrap_or_else(|e| e.into_inner());
+        state.expected += ratio;
+        let elapsed = state.window_start.elapsed();
+        if elapsed >= self.config.adaptive_window {
+            let rate = state.expected / elapsed.as_secs_f64();
+            state.factor = (budget / rate).min(1.0);
+            state.window_start = Instant::now();
+            state.expected = 0.0;
+        }
+        state.factor
+    }
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// 64-bit FNV-1a hash
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(GOLDEN_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(level: Level, key: Option<&str>) -> LogStashRecord {
+        let mut event = LogStashRecord::new();
+        event.level = level;
+        if let Some(key) = key {
+            event.add_data("trace_id", key.into());
+        }
+        event
+    }
+
+    #[test]
+    fn levels_without_ratio_are_kept() {
+        let sampler = Sampler::new(SamplingConfig::default().with_level(Level::Debug, 0.0));
+        let mut event = record(Level::Info, None);
+        assert!(sampler.sample(&mut event));
+        assert!(!event.fields.contains_key(SAMPLE_RATE_FIELD));
+
+        let mut event = record(Level::Debug, None);
+        assert!(!sampler.sample(&mut event));
+    }
+
+    #[test]
+    fn kept_records_get_sample_rate() {
+        let sampler = Sampler::new(SamplingConfig::default().with_level(Level::Debug, 0.5));
+        let mut kept = 0;
+        for _ in 0..1000 {
+            let mut event = record(Level::Debug, None);
+            if sampler.sample(&mut event) {
+                kept += 1;
+                assert_eq!(event.fields[SAMPLE_RATE_FIELD], 2.0);
+            }
+        }
+        assert!((400..600).contains(&kept), "{}", kept);
+    }
+
+    #[test]
+    fn records_with_same_key_share_decision() {
+        let config = SamplingConfig::default()
+            .with_level(Level::Debug, 0.5)
+            .with_key_field("trace_id");
+        let sampler = Sampler::new(config);
+        let mut kept_keys = 0;
+        for key in 0..100 {
+            let key = key.to_string();
+            let kept = sampler.sample(&mut record(Level::Debug, Some(&key)));
+            for _ in 0..5 {
+                assert_eq!(sampler.sample(&mut record(Level::Debug, Some(&key))), kept);
+            }
+            kept_keys += kept as usize;
+        }
+        assert!((25..75).contains(&kept_keys), "{}", kept_keys);
+    }
+
+    #[test]
+    fn adaptive_budget_lowers_ratio() {
+        let config = SamplingConfig::default()
+            .with_level(Level::Debug, 1.0)
+            .with_adaptive_budget(1.0)
+            .with_adaptive_window(Duration::from_millis(10));
+        let sampler = Sampler::new(config);
+        for _ in 0..100 {
+            sampler.sample(&mut record(Level::Debug, None));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        // The window ends with the first record
+        sampler.sample(&mut record(Level::Debug, None));
+        let kept = (0..1000)
+            .filter(|_| sampler.sample(&mut record(Level::Debug, None)))
+            .count();
+        assert!(kept < 100, "{}", kept);
+    }
+}
@@ -0,0 +1,585 @@
+use crate::prelude::*;
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const SEGMENT_EXTENSION: &str = "spool";
+const CURSOR_FILE: &str = "cursor";
+const LOCK_FILE: &str = "lock";
+
+/// When spool data is synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave it to the OS
+    Never,
+    /// Sync after every written record
+    Always,
+    /// Sync at most once per interval
+    Interval(Duration),
+}
+
+/// Settings of on-disk write-ahead spool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpoolConfig {
+    pub path: PathBuf,
+    /// Segment file is rotated once it reaches this size (in bytes)
+    pub segment_size: u64,
+    /// Oldest segments are removed to keep total spool size within this limit (in bytes),
+    /// including the segment being written
+    pub max_size: u64,
+    pub fsync: FsyncPolicy,
+    /// Delay before next send attempt of spooled records after failure
+    pub retry_interval: Duration,
+}
+
+impl SpoolConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            segment_size: 16 * 1024 * 1024,
+            max_size: 1024 * 1024 * 1024,
+            fsync: FsyncPolicy::Interval(Duration::from_secs(1)),
+            retry_interval: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+}
+
+/// Position in the spool: segment id and byte offset in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SpoolPosition {
+    segment: u64,
+    offset: u64,
+}
+
+/// Records read from the spool, acknowledged as a whole after successful send
+#[derive(Debug)]
+pub(crate) struct SpoolBatch {
+    pub(crate) events: Vec<LogStashRecord>,
+    position: SpoolPosition,
+    lines: usize,
+}
+
+impl SpoolBatch {
+    /// No complete records left after the cursor
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.lines == 0
+    }
+}
+
+/// Write-ahead spool of records stored as NDJSON in a sequence of segment files.
+///
+/// Records are appended to the last segment, acknowledged records are tracked by position
+/// saved in the cursor file. Unacknowledged records are replayed after restart. The directory
+/// is locked while the spool is open.
+#[derive(Debug)]
+pub(crate) struct Spool {
+    config: SpoolConfig,
+    /// Holds the directory lock
+    _lock: File,
+    /// Segment ids with their sizes, ordered from oldest to newest
+    segments: Vec<(u64, u64)>,
+    writer: File,
+    cursor: SpoolPosition,
+    pending: usize,
+    last_sync: Instant,
+}
+
+impl Spool {
+    /// Opens the spool, fails with [`Error::SpoolLocked`] if another instance uses the directory
+    pub(crate) fn open(config: SpoolConfig) -> Result<Self> {
+        fs::create_dir_all(&config.path)?;
+        let lock = lock_file(&config.path)?;
+        if let Err(err) = lock.try_lock_exclusive() {
+            if err.kind() == fs2::lock_contended_error().kind() {
+                return Err(Error::SpoolLocked(config.path.display().to_string()));
+            }
+            return Err(err.into());
+        }
+        Self::open_locked(config, lock)
+    }
+
+    /// Opens the spool, waits until another instance releases the directory
+    pub(crate) fn open_wait(config: SpoolConfig) -> Result<Self> {
+        fs::create_dir_all(&config.path)?;
+        let lock = lock_file(&config.path)?;
+        lock.lock_exclusive()?;
+        Self::open_locked(config, lock)
+    }
+
+    fn open_locked(config: SpoolConfig, lock: File) -> Result<Self> {
+        let mut segments = vec![];
+        for entry in fs::read_dir(&config.path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            {
+                segments.push((id, fs::metadata(&path)?.len()));
+            }
+        }
+        segments.sort_unstable();
+
+        let cursor = read_cursor(&config.path)?
+            .filter(|c| segments.iter().any(|(id, _)| *id == c.segment))
+            .unwrap_or_else(|| SpoolPosition {
+                segment: segments.first().map(|(id, _)| *id).unwrap_or(0),
+                offset: 0,
+            });
+        // Acknowledged segments are left if the process stopped before removing them
+        for &(id, _) in segments.iter().filter(|(id, _)| *id < cursor.segment) {
+            fs::remove_file(segment_path(&config.path, id))?;
+        }
+        segments.retain(|(id, _)| *id >= cursor.segment);
+        if segments.is_empty() {
+            segments.push((cursor.segment, 0));
+        }
+        // Always continue in a new segment, the last one may end with incomplete record
+        let (last, last_size) = *segments.last().expect("not empty");
+        if last_size > 0 {
+            segments.push((last + 1, 0));
+        }
+        let writer = open_segment(&config.path, segments.last().expect("not empty").0)?;
+
+        let mut spool = Self {
+            config,
+            _lock: lock,
+            segments,
+            writer,
+            cursor,
+            pending: 0,
+            last_sync: Instant::now(),
+        };
+        spool.remove_acknowledged_segments()?;
+        spool.pending = spool.count_pending()?;
+        Ok(spool)
+    }
+
+    /// Number of records not yet acknowledged
+    pub(crate) fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Position after the last appended record
+    pub(crate) fn end(&self) -> SpoolPosition {
+        let (segment, offset) = *self.segments.last().expect("not empty");
+        SpoolPosition { segment, offset }
+    }
+
+    /// All records before the position were acknowledged or dropped
+    pub(crate) fn is_acked(&self, position: SpoolPosition) -> bool {
+        self.pending == 0 || self.cursor >= position
+    }
+
+    pub(crate) fn retry_interval(&self) -> Duration {
+        self.config.retry_interval
+    }
+
+    /// Appends record, returns number of records removed due to size limit.
+    /// A record larger than the limit is not written and counted as removed.
+    pub(crate) fn append(&mut self, event: &LogStashRecord) -> Result<u64> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let len = line.len() as u64;
+        if len > self.config.max_size {
+            return Ok(1);
+        }
+
+        let (_, current_size) = *self.segments.last().expect("not empty");
+        if current_size > 0 && current_size + len > self.config.segment_size {
+            self.rotate()?;
+        }
+        let mut dropped = 0;
+        while self.total_size() + len > self.config.max_size {
+            // The only segment is not empty here, it is rotated to be removed as a whole
+            if self.segments.len() == 1 {
+                self.rotate()?;
+            }
+            dropped += self.drop_oldest_segment()?;
+        }
+
+        self.writer.write_all(&line)?;
+        self.segments.last_mut().expect("not empty").1 += len;
+        self.pending += 1;
+        self.sync_if_needed(false)?;
+        Ok(dropped)
+    }
+
+    /// Reads up to `max` records with total size up to `max_bytes` after the cursor
+    pub(crate) fn read(&mut self, max: usize, max_bytes: Option<usize>) -> Result<SpoolBatch> {
+        self.sync_if_needed(true)?;
+        let mut events = vec![];
+        let mut lines = 0;
+        let mut bytes = 0;
+        let mut position = self.cursor;
+        let mut full = false;
+        for &(segment, _) in self
+            .segments
+            .iter()
+            .filter(|(id, _)| *id >= self.cursor.segment)
+        {
+            let mut offset = if segment == self.cursor.segment {
+                self.cursor.offset
+            } else {
+                0
+            };
+            let mut file = File::open(segment_path(&self.config.path, segment))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(file);
+            let mut line = vec![];
+            while events.len() < max {
+                line.clear();
+                let read = reader.read_until(b'\n', &mut line)?;
+                // Incomplete record was cut by crash
+                if read == 0 || line.last() != Some(&b'\n') {
+                    break;
+                }
+                if lines > 0 && max_bytes.is_some_and(|max| bytes + read > max) {
+                    full = true;
+                    break;
+                }
+                offset += read as u64;
+                bytes += read;
+                lines += 1;
+                // Corrupted records are skipped
+                if let Ok(event) = serde_json::from_slice(&line) {
+                    events.push(event);
+                }
+            }
+            position = SpoolPosition { segment, offset };
+            if full || events.len() >= max {
+                break;
+            }
+        }
+        Ok(SpoolBatch {
+            events,
+            position,
+            lines,
+        })
+    }
+
+    /// Marks records of the batch as sent
+    pub(crate) fn ack(&mut self, batch: &SpoolBatch) -> Result<()> {
+        if batch.position <= self.cursor {
+            return Ok(());
+        }
+        self.cursor = batch.position;
+        self.pending = self.pending.saturating_sub(batch.lines);
+        write_cursor(&self.config.path, self.cursor)?;
+        self.remove_acknowledged_segments()?;
+        Ok(())
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.sync_if_needed(true)
+    }
+
+    fn sync_if_needed(&mut self, force: bool) -> Result<()> {
+        let sync = match self.config.fsync {
+            FsyncPolicy::Never => false,
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => force || self.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            self.writer.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn total_size(&self) -> u64 {
+        self.segments.iter().map(|(_, size)| size).sum()
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.sync_data()?;
+        let id = self.segments.last().expect("not empty").0 + 1;
+        self.writer = open_segment(&self.config.path, id)?;
+        self.segments.push((id, 0));
+        Ok(())
+    }
+
+    fn drop_oldest_segment(&mut self) -> Result<u64> {
+        let (id, _) = self.segments.remove(0);
+        let path = segment_path(&self.config.path, id);
+        let mut file = File::open(&path)?;
+        if id == self.cursor.segment {
+            file.seek(SeekFrom::Start(self.cursor.offset))?;
+        }
+        let dropped = count_lines(file)?;
+        fs::remove_file(path)?;
+        self.pending = self.pending.saturating_sub(dropped);
+        if id >= self.cursor.segment {
+            self.cursor = SpoolPosition {
+                segment: self.segments[0].0,
+                offset: 0,
+            };
+            write_cursor(&self.config.path, self.cursor)?;
+        }
+        Ok(dropped as u64)
+    }
+
+    fn remove_acknowledged_segments(&mut self) -> Result<()> {
+        while self.segments.len() > 1 && self.segments[0].0 < self.cursor.segment {
+            let (id, _) = self.segments.remove(0);
+            fs::remove_file(segment_path(&self.config.path, id))?;
+        }
+        Ok(())
+    }
+
+    fn count_pending(&mut self) -> Result<usize> {
+        let mut pending = 0;
+        let cursor = self.cursor;
+        for &(segment, _) in &self.segments {
+            let mut file = File::open(segment_path(&self.config.path, segment))?;
+            if segment == cursor.segment {
+                file.seek(SeekFrom::Start(cursor.offset))?;
+            }
+            pending += count_lines(file)?;
+        }
+        Ok(pending)
+    }
+}
+
+fn count_lines(file: File) -> Result<usize> {
+    let mut reader = BufReader::new(file);
+    let mut count = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(count);
+        }
+        count += buf.iter().filter(|b| **b == b'\n').count();
+        let len = buf.len();
+        reader.consume(len);
+    }
+}
+
+fn lock_file(dir: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?)
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn open_segment(dir: &Path, id: u64) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, id))?)
+}
+
+fn read_cursor(dir: &Path) -> Result<Option<SpoolPosition>> {
+    let data = match fs::read_to_string(dir.join(CURSOR_FILE)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut parts = data.split_whitespace().map(|p| p.parse::<u64>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(segment)), Some(Ok(offset))) => Ok(Some(SpoolPosition { segment, offset })),
+        _ => Ok(None),
+    }
+}
+
+fn write_cursor(dir: &Path, cursor: SpoolPosition) -> Result<()> {
+    // Write to temporary file and rename to keep cursor update atomic
+    let tmp = dir.join(format!("{}.tmp", CURSOR_FILE));
+    let mut file = File::create(&tmp)?;
+    write!(file, "{} {}", cursor.segment, cursor.offset)?;
+    file.sync_data()?;
+    fs::rename(tmp, dir.join(CURSOR_FILE))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("logstash-rs-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn record(message: &str) -> LogStashRecord {
+        let mut event = LogStashRecord::new();
+        event.add_data("message", message.into());
+        event
+    }
+
+    fn segment_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                let path = e.as_ref().unwrap().path();
+                path.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION)
+            })
+            .count()
+    }
+
+    #[test]
+    fn directory_is_locked_while_open() {
+        let dir = temp_dir("lock");
+        let spool = Spool::open(SpoolConfig::new(&dir)).unwrap();
+        let err = Spool::open(SpoolConfig::new(&dir)).unwrap_err();
+        assert!(matches!(err, Error::SpoolLocked(_)), "{:?}", err);
+
+        drop(spool);
+        Spool::open(SpoolConfig::new(&dir)).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn acknowledged_segments_are_removed_on_open() {
+        let dir = temp_dir("acked-segments");
+        let config = SpoolConfig::new(&dir).with_segment_size(1);
+        let mut spool = Spool::open(config.clone()).unwrap();
+        for i in 0..3 {
+            spool.append(&record(&format!("m{}", i))).unwrap();
+        }
+        let batch = spool.read(2, None).unwrap();
+        // Crash after the cursor is written, before segments are removed
+        write_cursor(&dir, batch.position).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(config).unwrap();
+        assert_eq!(spool.pending(), 1);
+        // The first segment is acknowledged, a new one is written after restart
+        assert!(!segment_path(&dir, 0).exists());
+        assert_eq!(segment_files(&dir), 3);
+        let batch = spool.read(10, None).unwrap();
+        assert_eq!(batch.events[0].fields["message"], "m2");
+        drop(spool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn messages(batch: &SpoolBatch) -> Vec<String> {
+        batch
+            .events
+            .iter()
+            .map(|e| e.fields["message"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn line_len(event: &LogStashRecord) -> u64 {
+        serde_json::to_vec(event).unwrap().len() as u64 + 1
+    }
+
+    #[test]
+    fn unacknowledged_records_are_replayed_after_reopen() {
+        let dir = temp_dir("replay");
+        let mut spool = Spool::open(SpoolConfig::new(&dir)).unwrap();
+        for i in 0..3 {
+            spool.append(&record(&format!("m{}", i))).unwrap();
+        }
+        let batch = spool.read(1, None).unwrap();
+        spool.ack(&batch).unwrap();
+        // Read but not acknowledged
+        spool.read(10, None).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(SpoolConfig::new(&dir)).unwrap();
+        assert_eq!(spool.pending(), 2);
+        let batch = spool.read(10, None).unwrap();
+        assert_eq!(messages(&batch), ["m1", "m2"]);
+        spool.ack(&batch).unwrap();
+        assert_eq!(spool.pending(), 0);
+        assert!(spool.read(10, None).unwrap().is_exhausted());
+        drop(spool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_records_are_dropped_over_max_size() {
+        let dir = temp_dir("overflow");
+        let len = line_len(&record("m0"));
+        let config = SpoolConfig::new(&dir)
+            .with_segment_size(len)
+            .with_max_size(2 * len);
+        let mut spool = Spool::open(config).unwrap();
+        assert_eq!(spool.append(&record("m0")).unwrap(), 0);
+        assert_eq!(spool.append(&record("m1")).unwrap(), 0);
+        assert_eq!(spool.append(&record("m2")).unwrap(), 1);
+        // Larger than the whole spool, not written
+        assert_eq!(spool.append(&record(&"x".repeat(1000))).unwrap(), 1);
+        assert_eq!(spool.pending(), 2);
+
+        let batch = spool.read(10, None).unwrap();
+        assert_eq!(messages(&batch), ["m1", "m2"]);
+        drop(spool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_is_limited_by_bytes() {
+        let dir = temp_dir("read-bytes");
+        let mut spool = Spool::open(SpoolConfig::new(&dir)).unwrap();
+        for i in 0..3 {
+            spool.append(&record(&format!("m{}", i))).unwrap();
+        }
+        let len = line_len(&record("m0")) as usize;
+        let batch = spool.read(10, Some(2 * len)).unwrap();
+        assert_eq!(messages(&batch), ["m0", "m1"]);
+        spool.ack(&batch).unwrap();
+        // A single record is read even if it exceeds the limit
+        let batch = spool.read(10, Some(1)).unwrap();
+        assert_eq!(messages(&batch), ["m2"]);
+        drop(spool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn incomplete_record_is_skipped_after_crash() {
+        let dir = temp_dir("crash");
+        let mut spool = Spool::open(SpoolConfig::new(&dir)).unwrap();
+        spool.append(&record("m0")).unwrap();
+        spool.append(&record("m1")).unwrap();
+        drop(spool);
+        // Record cut in the middle of writing
+        let mut file = open_segment(&dir, 0).unwrap();
+        file.write_all(b"{\"message\":\"m").unwrap();
+        drop(file);
+
+        let mut spool = Spool::open(SpoolConfig::new(&dir)).unwrap();
+        assert_eq!(spool.pending(), 2);
+        spool.append(&record("m2")).unwrap();
+        let batch = spool.read(10, None).unwrap();
+        assert_eq!(messages(&batch), ["m0", "m1", "m2"]);
+        spool.ack(&batch).unwrap();
+        assert_eq!(spool.pending(), 0);
+        drop(spool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}